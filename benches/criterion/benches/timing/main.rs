@@ -2,6 +2,12 @@ use criterion::criterion_main;
 
 mod message;
 
+mod netcode;
+
 mod replication;
 
-criterion_main!(message::message_benches, replication::replication_benches,);
+criterion_main!(
+    message::message_benches,
+    netcode::netcode_benches,
+    replication::replication_benches,
+);
@@ -0,0 +1,86 @@
+//! Benchmark to measure the cost of serializing, encrypting and decrypting netcode packets
+use criterion::{BatchSize, Criterion, Throughput, criterion_group};
+use lightyear_link::{LinkReceiver, RecvPayload, SendPayload};
+use lightyear_netcode::client::Client;
+use lightyear_netcode::testing::{LinkPair, step};
+use lightyear_netcode::{Server, generate_key};
+
+criterion_group!(netcode_benches, send_payload_packets, recv_payload_packets);
+
+const NUM_PACKETS: usize = 10_000;
+
+fn connect() -> (Client, Server, LinkPair) {
+    let mut server = Server::new(0, generate_key()).unwrap();
+    let token = server
+        .token(1, "127.0.0.1:5000".parse().unwrap())
+        .generate()
+        .unwrap();
+    let mut client = Client::new(&token.try_into_bytes().unwrap()).unwrap();
+    let mut links = LinkPair::new();
+    client.connect();
+    step(&mut client, &mut server, &mut links, 60).unwrap();
+    assert!(client.is_connected());
+    (client, server, links)
+}
+
+/// Sending N payload packets from a connected client
+fn send_payload_packets(criterion: &mut Criterion) {
+    let (mut client, _server, mut links) = connect();
+
+    let payload = SendPayload::from_static(&[0; 100]);
+    let mut group = criterion.benchmark_group("netcode/send_payload_packets");
+    group.warm_up_time(core::time::Duration::from_millis(500));
+    group.measurement_time(core::time::Duration::from_millis(3000));
+    group.throughput(Throughput::Elements(NUM_PACKETS as u64));
+    group.bench_function(
+        criterion::BenchmarkId::new("num_packets", NUM_PACKETS),
+        |bencher| {
+            bencher.iter(|| {
+                for _ in 0..NUM_PACKETS {
+                    client
+                        .send(payload.clone(), &mut links.client.send)
+                        .unwrap();
+                }
+                links.client.send.drain().for_each(drop);
+            });
+        },
+    );
+    group.finish();
+}
+
+/// Receiving N payload packets of 1200 bytes on a connected client
+fn recv_payload_packets(criterion: &mut Criterion) {
+    let (mut client, mut server, mut links) = connect();
+
+    let payload = SendPayload::from_static(&[0; 1200]);
+    let mut group = criterion.benchmark_group("netcode/recv_payload_packets");
+    group.warm_up_time(core::time::Duration::from_millis(500));
+    group.measurement_time(core::time::Duration::from_millis(3000));
+    group.throughput(Throughput::Elements(NUM_PACKETS as u64));
+    group.bench_function(
+        criterion::BenchmarkId::new("num_packets", NUM_PACKETS),
+        |bencher| {
+            bencher.iter_batched(
+                || {
+                    for _ in 0..NUM_PACKETS {
+                        server
+                            .send(payload.clone(), 1, &mut links.server.send)
+                            .unwrap();
+                    }
+                    // the packets received from a transport are not shared with any other buffer
+                    let mut receiver = LinkReceiver::default();
+                    links.server.send.drain().for_each(|packet| {
+                        receiver.push_raw(RecvPayload::copy_from_slice(&packet))
+                    });
+                    receiver
+                },
+                |mut receiver| {
+                    client.update(0.0, &mut receiver);
+                    receiver.drain().for_each(drop);
+                },
+                BatchSize::PerIteration,
+            );
+        },
+    );
+    group.finish();
+}
@@ -3,7 +3,8 @@ use bevy_reflect::Reflect;
 use core::time::Duration;
 use lightyear_core::time::Instant;
 use lightyear_utils::ready_buffer::ReadyBuffer;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 /// Contains configuration required to initialize a LinkConditioner
 #[derive(Clone, Debug, Default, Reflect)]
@@ -23,6 +24,7 @@ pub struct LinkConditionerConfig {
 pub struct LinkConditioner<P: Eq> {
     config: LinkConditionerConfig,
     pub time_queue: ReadyBuffer<Instant, P>,
+    rng: StdRng,
 }
 
 impl<P: Eq> LinkConditioner<P> {
@@ -30,6 +32,19 @@ impl<P: Eq> LinkConditioner<P> {
         LinkConditioner {
             config,
             time_queue: ReadyBuffer::new(),
+            rng: StdRng::from_rng(&mut rand::rng()),
+        }
+    }
+
+    /// Creates a LinkConditioner whose random loss and jitter are drawn from a generator seeded with `seed`.
+    ///
+    /// Conditioners created with the same seed and config drop and delay the same packets,
+    /// which makes tests that run under adverse network conditions reproducible.
+    pub fn with_seed(config: LinkConditionerConfig, seed: u64) -> Self {
+        LinkConditioner {
+            config,
+            time_queue: ReadyBuffer::new(),
+            rng: StdRng::seed_from_u64(seed),
         }
     }
 
@@ -37,15 +52,14 @@ impl<P: Eq> LinkConditioner<P> {
     ///
     /// `elapsed`: Duration since app start
     pub(crate) fn condition_packet(&mut self, packet: P, instant: Instant) {
-        let mut rng = rand::rng();
-        if rng.random_range(0.0..1.0) <= self.config.incoming_loss {
+        if self.rng.random_range(0.0..1.0) <= self.config.incoming_loss {
             return;
         }
         let mut latency: i32 = self.config.incoming_latency.as_millis() as i32;
         let mut packet_timestamp = instant;
         if self.config.incoming_jitter > Duration::default() {
             let jitter: i32 = self.config.incoming_jitter.as_millis() as i32;
-            latency += rng.random_range(-jitter..jitter);
+            latency += self.rng.random_range(-jitter..jitter);
         }
         if latency > 0 {
             packet_timestamp += Duration::from_millis(latency as u64);
@@ -108,3 +122,29 @@ impl LinkConditionerConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn seeded_conditioners_are_reproducible() {
+        let config =
+            LinkConditionerConfig::new(Duration::from_millis(100), Duration::from_millis(50), 0.5);
+        let start = Instant::now();
+        let delivered = |seed| {
+            let mut conditioner = LinkConditioner::with_seed(config.clone(), seed);
+            (0..100u32).for_each(|i| conditioner.condition_packet(i, start));
+            let mut delivered = Vec::new();
+            for ms in 0..200 {
+                while let Some(packet) = conditioner.pop_packet(start + Duration::from_millis(ms)) {
+                    delivered.push((ms, packet));
+                }
+            }
+            delivered
+        };
+        assert_eq!(delivered(42), delivered(42));
+        assert!(delivered(42).len() < 100);
+    }
+}
@@ -3,7 +3,7 @@ use alloc::{format, string::ToString};
 use crate::Error;
 use crate::auth::Authentication;
 use crate::client::{ClientConfig, ClientState};
-use aeronet_io::connection::PeerAddr;
+use aeronet_io::connection::{LocalAddr, PeerAddr};
 use bevy_app::{App, Plugin, PostUpdate, PreUpdate};
 use bevy_ecs::lifecycle::HookContext;
 use bevy_ecs::prelude::*;
@@ -16,9 +16,9 @@ use lightyear_connection::client::{
 };
 use lightyear_connection::host::HostClient;
 use lightyear_core::id::{LocalId, PeerId, RemoteId};
-use lightyear_link::{Link, LinkSystems, Linked};
+use lightyear_link::{Link, LinkSystems, Linked, Linking};
 use lightyear_transport::plugin::TransportSystems;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, trace};
 
 pub struct NetcodeClientPlugin;
 
@@ -48,6 +48,31 @@ impl NetcodeClient {
     }
 }
 
+/// Message written when a netcode client connects to the server.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientConnected {
+    pub entity: Entity,
+}
+
+/// Message written when a netcode client stops being connected or connecting to the server.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientDisconnected {
+    pub entity: Entity,
+    /// The state the client ended up in, e.g. [`ClientState::ConnectionTimedOut`].
+    pub reason: ClientState,
+}
+
+/// Message written when the state of a netcode client changes during an update.
+///
+/// This mirrors the [`on_state_change`](crate::client::ClientConfig::on_state_change) callback, but if the client
+/// goes through several states in a single update, only the state before and after the update are reported.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientStateChanged {
+    pub entity: Entity,
+    pub from: ClientState,
+    pub to: ClientState,
+}
+
 #[derive(Clone, Reflect)]
 /// Config related to the netcode protocol (abstraction of a connection over raw UDP-like transport)
 pub struct NetcodeConfig {
@@ -113,7 +138,7 @@ impl NetcodeClientPlugin {
                 if let Some(payload) = link.send.pop() {
                     client
                         .inner
-                        .send(payload, &mut link.send)
+                        .send_or_drop(payload, &mut link.send)
                         .inspect_err(|e| {
                             error!("Error sending packet: {:?}", e);
                         })
@@ -124,6 +149,11 @@ impl NetcodeClientPlugin {
             // send netcode internal packets
             client.inner.drain_send_netcode_packets(&mut link.send);
 
+            // the link only reaches the current server
+            for (addr, _) in client.inner.drain_parallel_requests() {
+                trace!("dropping parallel connection request to {addr}");
+            }
+
             // #[cfg(feature = "test_utils")]
             // trace!("CLIENT: length of each packet in send: {:?}", link.send.iter().map(|p| p.len()).collect::<Vec<_>>());
         })
@@ -154,6 +184,7 @@ impl NetcodeClientPlugin {
 
                 // Buffer the packets received from the link into the Connection
                 // don't short-circuit on error
+                let previous_state = client.inner.state();
                 if let Ok(state) = client
                     .inner
                     .try_update(delta.as_secs_f64(), &mut link.recv)
@@ -161,6 +192,16 @@ impl NetcodeClientPlugin {
                         error!("Error receiving packet: {:?}", e);
                     })
                 {
+                    if state != previous_state {
+                        parallel_commands.command_scope(|mut commands| {
+                            Self::write_state_messages(
+                                &mut commands,
+                                entity,
+                                previous_state,
+                                state,
+                            );
+                        });
+                    }
                     if state == ClientState::Connected && connecting {
                         info!("Client {} connected", client.id());
                         parallel_commands.command_scope(|mut commands| {
@@ -190,6 +231,49 @@ impl NetcodeClientPlugin {
             })
     }
 
+    /// Mirror a state transition of the client as bevy messages
+    fn write_state_messages(
+        commands: &mut Commands,
+        entity: Entity,
+        from: ClientState,
+        to: ClientState,
+    ) {
+        if from == to {
+            return;
+        }
+        let is_active = |state| {
+            matches!(
+                state,
+                ClientState::Connected
+                    | ClientState::SendingConnectionRequest
+                    | ClientState::SendingChallengeResponse
+            )
+        };
+        commands.write_message(ClientStateChanged { entity, from, to });
+        if to == ClientState::Connected {
+            commands.write_message(ClientConnected { entity });
+        } else if is_active(from) && !is_active(to) {
+            commands.write_message(ClientDisconnected { entity, reason: to });
+        }
+    }
+
+    /// Hold off sending netcode packets while the underlying IO is still opening
+    fn on_linking(trigger: On<Add, Linking>, mut query: Query<&mut NetcodeClient>) {
+        if let Ok(mut client) = query.get_mut(trigger.entity) {
+            client.inner.set_transport_ready(false);
+        }
+    }
+
+    fn on_linked(
+        trigger: On<Add, Linked>,
+        mut query: Query<(&mut NetcodeClient, Option<&LocalAddr>)>,
+    ) {
+        if let Ok((mut client, local_addr)) = query.get_mut(trigger.entity) {
+            client.inner.set_transport_ready(true);
+            client.inner.set_local_addr(local_addr.map(|addr| addr.0));
+        }
+    }
+
     fn connect(
         trigger: On<Connect>,
         mut commands: Commands,
@@ -197,7 +281,14 @@ impl NetcodeClientPlugin {
     ) {
         if let Ok(mut client) = query.get_mut(trigger.entity) {
             debug!("Starting netcode connection process");
+            let previous_state = client.inner.state();
             client.inner.connect();
+            Self::write_state_messages(
+                &mut commands,
+                trigger.entity,
+                previous_state,
+                client.inner.state(),
+            );
             commands.entity(trigger.entity).insert(Connecting);
         }
     }
@@ -208,7 +299,14 @@ impl NetcodeClientPlugin {
         mut query: Query<&mut NetcodeClient, Without<Disconnected>>,
     ) -> Result {
         if let Ok(mut client) = query.get_mut(trigger.entity) {
+            let previous_state = client.inner.state();
             client.inner.disconnect()?;
+            Self::write_state_messages(
+                &mut commands,
+                trigger.entity,
+                previous_state,
+                client.inner.state(),
+            );
             commands.entity(trigger.entity).insert(Disconnected {
                 reason: Some("Client trigger".to_string()),
             });
@@ -241,8 +339,13 @@ impl Plugin for NetcodeClientPlugin {
                 .chain(),
         );
 
+        app.add_message::<ClientConnected>();
+        app.add_message::<ClientDisconnected>();
+        app.add_message::<ClientStateChanged>();
         app.add_systems(PreUpdate, Self::receive.in_set(ConnectionSystems::Receive));
         app.add_systems(PostUpdate, Self::send.in_set(ConnectionSystems::Send));
+        app.add_observer(Self::on_linking);
+        app.add_observer(Self::on_linked);
         app.add_observer(Self::connect);
         app.add_observer(Self::disconnect);
     }
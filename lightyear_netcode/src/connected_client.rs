@@ -0,0 +1,402 @@
+/*! A [`Client`] bundled with its [`Link`] and the IO that carries its packets.
+
+Using the netcode [`Client`] directly requires shuttling bytes by hand between the IO, the [`Link`] and the client,
+in the right order. [`ConnectedClient`] does it in a single [`pump`](ConnectedClient::pump):
+1. the packets received by the [`PacketIo`] are buffered in the link
+2. the client processes them and updates its state
+3. the payloads queued with [`send`](ConnectedClient::send) and the netcode packets are sent through the [`PacketIo`],
+   to the address of the server that the client is connecting or connected to
+
+When several IOs can reach the same server (e.g. WebSocket and WebTransport in the browser),
+a [`TransportSelector`] races them and keeps the first one that completes the netcode handshake.
+*/
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::net::SocketAddr;
+use lightyear_link::{Link, LinkReceiver, RecvPayload, SendPayload};
+use tracing::debug;
+
+#[cfg(all(feature = "std", not(target_family = "wasm")))]
+use crate::ConnectToken;
+use crate::client::{Client, ClientState};
+use crate::error::{Error, Result};
+
+/// The IO used by a [`ConnectedClient`] to exchange packets with the servers.
+///
+/// The client can send packets to any server of its connect token (when it fails over to the next server,
+/// or with [`ClientConfig::parallel_connect`](crate::client::ClientConfig::parallel_connect)).
+/// An IO that can only reach a single server (e.g. a WebSocket) can ignore the addresses.
+pub trait PacketIo {
+    /// Sends a packet to the server at `addr`.
+    fn send(&mut self, packet: &[u8], addr: SocketAddr) -> Result<()>;
+    /// Returns the next packet received from the servers, with the address it was received from
+    /// if the IO knows it, or `None` if there are none.
+    /// This must not block.
+    fn recv(&mut self) -> Result<Option<(RecvPayload, Option<SocketAddr>)>>;
+    /// Returns the local address the IO is bound to, if it is known.
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// A [`PacketIo`] over an unconnected [`UdpSocket`](std::net::UdpSocket) set to non-blocking,
+/// which can reach every server of the connect token.
+#[cfg(all(feature = "std", not(target_family = "wasm")))]
+impl PacketIo for std::net::UdpSocket {
+    fn send(&mut self, packet: &[u8], addr: SocketAddr) -> Result<()> {
+        std::net::UdpSocket::send_to(self, packet, addr)?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Option<(RecvPayload, Option<SocketAddr>)>> {
+        let mut buf = [0u8; crate::MAX_PKT_BUF_SIZE];
+        match std::net::UdpSocket::recv_from(self, &mut buf) {
+            Ok((len, source)) => Ok(Some((
+                RecvPayload::copy_from_slice(&buf[..len]),
+                Some(source),
+            ))),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        std::net::UdpSocket::local_addr(self).ok()
+    }
+}
+
+/// Creates the default [`PacketIo`] to reach the servers of a connect token.
+///
+/// On native platforms, this is a non-blocking [`UdpSocket`](std::net::UdpSocket) bound to an ephemeral port.
+/// The socket is not connected: each packet is sent to the server that the client is currently trying,
+/// so the client can fail over to every server of the token, in the order chosen by the client
+/// (the token's order by default, see [`ClientConfig::server_weights`](crate::client::ClientConfig::server_weights)
+/// and [`Client::set_preferred_server`]).
+///
+/// The socket is bound to the address family of the first server of the token. If the token mixes IPv4
+/// and IPv6 servers, the servers of the other family can't be reached through it, and the client fails over past them.
+///
+/// There is no default IO on the other platforms (e.g. WebSocket or WebTransport in the browser):
+/// create the IO yourself and pass it to [`ConnectedClient::new`], or race several of them with a [`TransportSelector`].
+/// The same applies to override the default on native platforms.
+#[cfg(all(feature = "std", not(target_family = "wasm")))]
+pub fn default_io(token: &ConnectToken) -> Result<std::net::UdpSocket> {
+    let (_, first_addr) = token
+        .server_addresses
+        .iter()
+        .next()
+        .ok_or(Error::InvalidConnectToken)?;
+    let local_addr = match first_addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+    let socket = std::net::UdpSocket::bind(local_addr)?;
+    socket.set_nonblocking(true)?;
+    debug!(
+        "bound udp socket {:?} for the servers of the token",
+        socket.local_addr()
+    );
+    Ok(socket)
+}
+
+/// A netcode [`Client`] that owns its [`Link`] and [`PacketIo`].
+///
+/// See the [module-level documentation](self) for more details.
+pub struct ConnectedClient<Ctx = ()> {
+    client: Client<Ctx>,
+    link: Link,
+    io: Box<dyn PacketIo + Send + Sync>,
+    // payloads queued by the user, sent once the client is connected
+    outbound: VecDeque<SendPayload>,
+}
+
+impl<Ctx> ConnectedClient<Ctx> {
+    pub fn new(client: Client<Ctx>, io: impl PacketIo + Send + Sync + 'static) -> Self {
+        Self::from_boxed(client, Box::new(io))
+    }
+
+    fn from_boxed(mut client: Client<Ctx>, io: Box<dyn PacketIo + Send + Sync>) -> Self {
+        client.set_local_addr(io.local_addr());
+        Self {
+            client,
+            link: Link::default(),
+            io,
+            outbound: VecDeque::new(),
+        }
+    }
+
+    /// Moves the received packets from the IO to the link, updates the client,
+    /// then sends the pending packets through the IO.
+    ///
+    /// `delta` is the time elapsed since the last call, as in [`Client::try_update`].
+    pub fn pump(&mut self, delta: f64) -> Result<ClientState> {
+        let mut inbound = Vec::new();
+        while let Some(packet) = self.io.recv()? {
+            inbound.push(packet);
+        }
+        // consecutive packets from the same source are processed together, so that the client
+        // knows which server answered when it contacts several of them
+        // the client is updated at least once, even if no packets were received
+        let mut packets = inbound.into_iter().peekable();
+        let mut first = true;
+        while let Some(source) = packets
+            .peek()
+            .map(|(_, source)| *source)
+            .or(first.then_some(None))
+        {
+            let mut receiver = LinkReceiver::default();
+            while let Some((packet, _)) = packets.next_if(|(_, s)| *s == source) {
+                receiver.push_raw(packet);
+            }
+            // the time is only advanced once
+            let delta = if first { delta } else { 0.0 };
+            first = false;
+            match source {
+                Some(source) => self
+                    .client
+                    .try_update_with_source(delta, &mut receiver, source)?,
+                None => self.client.try_update(delta, &mut receiver)?,
+            };
+            // only the payloads are left in the receiver
+            receiver
+                .drain()
+                .for_each(|payload| self.link.recv.push_raw(payload));
+        }
+
+        if self.client.is_connected() {
+            while let Some(payload) = self.outbound.pop_front() {
+                self.client.send(payload, &mut self.link.send)?;
+            }
+        }
+        self.client.drain_send_netcode_packets(&mut self.link.send);
+        let server_addr = self.client.server_addr();
+        for packet in self.link.send.drain() {
+            self.io.send(&packet, server_addr)?;
+        }
+        for (addr, packet) in self.client.drain_parallel_requests() {
+            self.io.send(&packet, addr)?;
+        }
+        Ok(self.client.state())
+    }
+
+    /// Queues a payload to be sent to the server.
+    ///
+    /// The payloads are sent during the first [`pump`](ConnectedClient::pump) in which the client is connected,
+    /// so the payloads queued while the client is still connecting are kept until the connection is established.
+    pub fn send(&mut self, payload: SendPayload) {
+        self.outbound.push_back(payload);
+    }
+
+    /// Returns the next payload received from the server.
+    pub fn recv(&mut self) -> Option<RecvPayload> {
+        self.link.recv.pop()
+    }
+
+    pub fn client(&self) -> &Client<Ctx> {
+        &self.client
+    }
+
+    pub fn client_mut(&mut self) -> &mut Client<Ctx> {
+        &mut self.client
+    }
+}
+
+type ClientFactory<Ctx> = Box<dyn FnMut() -> Result<Client<Ctx>> + Send + Sync>;
+
+/// Races several candidate [`PacketIo`]s to the same server and keeps the first one that connects,
+/// similar to Happy Eyeballs for IPv4/IPv6.
+///
+/// The candidates are started in the order they were added, each one `stagger` seconds after the previous one,
+/// so that a preferred transport gets a head start. Each candidate gets its own [`Client`] created by the factory,
+/// which should create clients from the same connect token.
+/// As soon as one of them reaches [`ClientState::Connected`], the others are cancelled and dropped.
+/// A candidate that fails (its client gives up connecting, or its IO returns an error) is dropped from the race.
+///
+/// Note that the server accepts a connect token from a single address: if the candidates reach the server
+/// from the same address, the later candidates only act as a fallback for the earlier ones.
+pub struct TransportSelector<Ctx = ()> {
+    new_client: ClientFactory<Ctx>,
+    stagger: f64,
+    elapsed: f64,
+    /// Candidates that haven't been started yet
+    pending: VecDeque<Box<dyn PacketIo + Send + Sync>>,
+    started: usize,
+    racing: Vec<ConnectedClient<Ctx>>,
+}
+
+impl<Ctx> TransportSelector<Ctx> {
+    /// Creates a selector that starts a candidate every `stagger` seconds,
+    /// using `new_client` to create the client bound to each candidate.
+    pub fn new(
+        stagger: f64,
+        new_client: impl FnMut() -> Result<Client<Ctx>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            new_client: Box::new(new_client),
+            stagger,
+            elapsed: 0.0,
+            pending: VecDeque::new(),
+            started: 0,
+            racing: Vec::new(),
+        }
+    }
+
+    /// Adds a candidate to the race. Candidates are started in the order they are added.
+    pub fn with_candidate(mut self, io: impl PacketIo + Send + Sync + 'static) -> Self {
+        self.pending.push_back(Box::new(io));
+        self
+    }
+
+    /// Starts the candidates that are due and pumps the ones in the race.
+    ///
+    /// Returns the client bound to the winning candidate once it is connected, or `None` while the race is ongoing.
+    /// Returns [`Error::NoTransportConnected`] if every candidate failed.
+    /// `delta` is the time elapsed since the last call, as in [`ConnectedClient::pump`].
+    pub fn poll(&mut self, delta: f64) -> Result<Option<ConnectedClient<Ctx>>> {
+        self.elapsed += delta;
+        let mut newly_started = 0;
+        while !self.pending.is_empty() && self.elapsed >= self.started as f64 * self.stagger {
+            let io = self.pending.pop_front().unwrap();
+            let mut client = (self.new_client)()?;
+            client.connect();
+            debug!("starting transport candidate {}", self.started);
+            self.started += 1;
+            newly_started += 1;
+            self.racing.push(ConnectedClient::from_boxed(client, io));
+        }
+
+        // the candidates started during this call didn't exist during `delta`
+        let mut num_started_before = self.racing.len() - newly_started;
+        let mut i = 0;
+        while i < self.racing.len() {
+            let candidate_delta = if i < num_started_before { delta } else { 0.0 };
+            match self.racing[i].pump(candidate_delta) {
+                Ok(ClientState::Connected) => {
+                    let winner = self.racing.swap_remove(i);
+                    debug!("transport candidate connected, cancelling the others");
+                    self.racing.clear();
+                    self.pending.clear();
+                    return Ok(Some(winner));
+                }
+                Ok(_) if self.racing[i].client.is_pending() => i += 1,
+                Ok(state) => {
+                    debug!("transport candidate failed to connect: {state:?}");
+                    self.racing.remove(i);
+                    if i < num_started_before {
+                        num_started_before -= 1;
+                    }
+                }
+                Err(e) => {
+                    debug!("transport candidate failed: {e:?}");
+                    self.racing.remove(i);
+                    if i < num_started_before {
+                        num_started_before -= 1;
+                    }
+                }
+            }
+        }
+        if self.racing.is_empty() && self.pending.is_empty() {
+            return Err(Error::NoTransportConnected);
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(all(test, feature = "std", not(target_family = "wasm")))]
+mod tests {
+    use super::*;
+    use crate::{ConnectToken, generate_key, packet::Packet};
+    use alloc::sync::Arc;
+    use std::sync::Mutex;
+
+    #[derive(Default, Clone)]
+    struct MemoryIo {
+        sent: Arc<Mutex<Vec<Vec<u8>>>>,
+        inbound: Arc<Mutex<VecDeque<(RecvPayload, Option<SocketAddr>)>>>,
+        broken: Arc<Mutex<bool>>,
+    }
+
+    impl PacketIo for MemoryIo {
+        fn send(&mut self, packet: &[u8], _: SocketAddr) -> Result<()> {
+            if *self.broken.lock().unwrap() {
+                return Err(
+                    no_std_io2::io::Error::from(no_std_io2::io::ErrorKind::BrokenPipe).into(),
+                );
+            }
+            self.sent.lock().unwrap().push(packet.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<Option<(RecvPayload, Option<SocketAddr>)>> {
+            Ok(self.inbound.lock().unwrap().pop_front())
+        }
+    }
+
+    fn token_bytes() -> [u8; crate::CONNECT_TOKEN_BYTES] {
+        ConnectToken::build("127.0.0.1:40000", 0, 1, generate_key())
+            .generate()
+            .unwrap()
+            .try_into_bytes()
+            .unwrap()
+    }
+
+    #[test]
+    fn default_io_is_not_connected_to_a_single_server() {
+        let token = ConnectToken::try_from_bytes(&token_bytes()).unwrap();
+        let socket = default_io(&token).unwrap();
+        assert!(socket.peer_addr().is_err());
+        assert!(PacketIo::local_addr(&socket).unwrap().is_ipv4());
+    }
+
+    #[test]
+    fn pump_sends_packets_through_io() {
+        let token = token_bytes();
+        let io = MemoryIo::default();
+        let mut client = ConnectedClient::new(Client::new(&token).unwrap(), io.clone());
+        client.client_mut().connect();
+        client.send(SendPayload::from_static(b"too early"));
+        client.pump(0.0).unwrap();
+
+        // only the connection request is sent, the payload waits for the connection
+        let sent = io.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][0], Packet::REQUEST);
+        assert_eq!(client.outbound.len(), 1);
+    }
+
+    #[test]
+    fn transport_selector_staggers_candidates() {
+        let token = token_bytes();
+        let first = MemoryIo::default();
+        let second = MemoryIo::default();
+        let mut selector = TransportSelector::new(0.1, move || Client::new(&token))
+            .with_candidate(first.clone())
+            .with_candidate(second.clone());
+
+        // only the first candidate is started
+        assert!(selector.poll(0.0).unwrap().is_none());
+        assert_eq!(first.sent.lock().unwrap().len(), 1);
+        assert!(second.sent.lock().unwrap().is_empty());
+
+        // the second candidate is started after the stagger delay
+        assert!(selector.poll(0.1).unwrap().is_none());
+        assert_eq!(second.sent.lock().unwrap().len(), 1);
+
+        // the race fails once every candidate failed
+        *first.broken.lock().unwrap() = true;
+        *second.broken.lock().unwrap() = true;
+        assert!(matches!(
+            selector.poll(1.0),
+            Err(Error::NoTransportConnected)
+        ));
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    #[test]
+    fn udp_io_reports_bound_address() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let bound = socket.local_addr().unwrap();
+        assert_ne!(bound.port(), 0);
+        let client = ConnectedClient::new(Client::new(&token_bytes()).unwrap(), socket);
+        assert_eq!(client.client().local_addr(), Some(bound));
+    }
+}
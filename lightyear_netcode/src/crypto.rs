@@ -59,6 +59,11 @@ pub fn try_generate_key() -> Result<Key> {
     Ok(key)
 }
 
+/// Generates a random `u64`. Panics if the underlying RNG fails (highly unlikely).
+pub(crate) fn random_u64() -> u64 {
+    OsRng.next_u64()
+}
+
 pub fn chacha_encrypt(
     buf: &mut [u8],
     associated_data: Option<&[u8]>,
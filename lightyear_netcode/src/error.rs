@@ -19,6 +19,23 @@ pub enum Error {
     EntityNotFound(Entity),
     #[error("tried to send a packet to a client that isn't connected")]
     ClientNotConnected(PeerId),
+    #[error("client_id {0} didn't allow rekeying during the handshake")]
+    RekeyNotAllowed(PeerId),
+    #[cfg(feature = "client")]
+    #[error("tried to send a payload but the client is not connected to the server (state: {0:?})")]
+    NotConnected(crate::client::ClientState),
+    #[error("none of the candidate transports could connect to the server")]
+    NoTransportConnected,
+    #[error("invalid packet send rate {0}, the rate must be a positive number of seconds")]
+    InvalidSendRate(f64),
+    #[error("invalid replay protection window {0}, the size must be a power of two")]
+    InvalidReplayWindow(usize),
+    #[error("invalid mask of allowed packets {0:#010b}, at least one packet type must be allowed")]
+    InvalidPacketMask(u8),
+    #[error("the connect token has no server at index {0}")]
+    InvalidServerIndex(usize),
+    #[error("the server needs at least one private key")]
+    NoPrivateKey,
     #[error("failed to read connect token")]
     InvalidConnectToken,
     #[error("client_id {0} connect token has already been used")]
@@ -65,11 +65,15 @@ extern crate std;
 pub use client_plugin::NetcodeClient;
 pub use crypto::{Key, generate_key, try_generate_key};
 pub use error::{Error, Result};
+pub use obfuscation::{OBFUSCATION_KEY_BYTES, ObfuscationKey};
+pub use packet::DisconnectCode;
 #[cfg(feature = "server")]
-pub use server::{Callback, ConnectCallback, Server, ServerConfig};
+pub use server::{
+    Callback, ConnectCallback, DisconnectCallback, DisconnectReason, Server, ServerConfig,
+};
 #[cfg(feature = "server")]
 pub use server_plugin::{NetcodeServer, TokenUserData};
-pub use token::{ConnectToken, ConnectTokenBuilder, InvalidTokenError};
+pub use token::{ConnectToken, ConnectTokenBuilder, ConnectTokenInfo, InvalidTokenError};
 
 /// The client id from a connect token, must be unique for each client.
 pub(crate) type ClientId = u64;
@@ -77,9 +81,13 @@ pub(crate) type ClientId = u64;
 mod bytes;
 #[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "client")]
+pub mod connected_client;
 mod crypto;
 pub(crate) mod error;
+mod obfuscation;
 mod packet;
+pub mod ping;
 mod replay;
 #[cfg(feature = "server")]
 mod server;
@@ -92,13 +100,22 @@ pub mod client_plugin;
 pub mod auth;
 #[cfg(feature = "server")]
 pub mod server_plugin;
+#[cfg(all(feature = "client", feature = "std"))]
+pub mod session;
+pub mod stream;
+#[cfg(all(feature = "test_utils", feature = "client", feature = "server"))]
+pub mod testing;
+pub mod transform;
 
 pub mod prelude {
     pub use crate::auth::Authentication;
 
     #[cfg(feature = "client")]
     pub mod client {
-        pub use crate::client_plugin::{NetcodeClient, NetcodeClientPlugin, NetcodeConfig};
+        pub use crate::client_plugin::{
+            ClientConnected, ClientDisconnected, ClientStateChanged, NetcodeClient,
+            NetcodeClientPlugin, NetcodeConfig,
+        };
     }
 
     #[cfg(feature = "server")]
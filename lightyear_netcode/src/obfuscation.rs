@@ -0,0 +1,52 @@
+use bytes::BytesMut;
+use lightyear_link::RecvPayload;
+
+/// The size of an [`ObfuscationKey`] in bytes.
+pub const OBFUSCATION_KEY_BYTES: usize = 32;
+
+/// A key used to obfuscate the netcode packets on the wire.
+///
+/// The key is XOR-ed with the fully serialized packets right before they are sent, and the same
+/// transform is applied to the received bytes before they are read.
+///
+/// This is **obfuscation, not encryption**: the packets are already encrypted and authenticated by netcode.
+/// It only hides the recognizable structure of netcode packets (prefix byte, sequence number, etc.)
+/// to defeat trivial protocol fingerprinting. The client and the server must use the same key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObfuscationKey([u8; OBFUSCATION_KEY_BYTES]);
+
+impl ObfuscationKey {
+    pub const fn new(key: [u8; OBFUSCATION_KEY_BYTES]) -> Self {
+        Self(key)
+    }
+
+    /// Applies the transform to `buf` in place. Applying it twice restores the original bytes.
+    pub(crate) fn apply(&self, buf: &mut [u8]) {
+        buf.iter_mut()
+            .zip(self.0.iter().cycle())
+            .for_each(|(b, k)| *b ^= k);
+    }
+
+    /// Reverses the transform on a packet received from the network.
+    pub(crate) fn reverse(&self, buf: RecvPayload) -> RecvPayload {
+        let mut buf = BytesMut::from(buf);
+        self.apply(&mut buf);
+        buf.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_is_reversible() {
+        let key = ObfuscationKey::new([0xA5; OBFUSCATION_KEY_BYTES]);
+        let original = [0u8, 1, 2, 3, 255];
+        let mut buf = original;
+        key.apply(&mut buf);
+        assert_ne!(buf, original);
+        let buf = key.reverse(RecvPayload::copy_from_slice(&buf));
+        assert_eq!(&buf[..], &original);
+    }
+}
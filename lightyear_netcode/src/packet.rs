@@ -20,7 +20,7 @@ use super::{
 use chacha20poly1305::XNonce;
 use lightyear_link::{RecvPayload, SendPayload};
 use lightyear_serde::reader::{ReadInteger, Reader};
-use lightyear_serde::writer::WriteInteger;
+use lightyear_serde::writer::{WriteInteger, Writer};
 use lightyear_serde::{SerializationError, ToBytes};
 use tracing::debug;
 
@@ -30,6 +30,8 @@ use lightyear_connection::shared::DeniedReason;
 pub enum Error {
     #[error("packet type {0} is invalid")]
     InvalidType(u8),
+    #[error("packet type {0} is not allowed")]
+    NotAllowed(u8),
     #[error("sequence bytes {0} are out of range [1, 8]")]
     InvalidSequenceBytes(u8),
     #[error("packet length is less than 1")]
@@ -82,6 +84,9 @@ pub struct RequestPacket {
     pub expire_timestamp: u64,
     pub token_nonce: XNonce,
     pub token_data: Box<[u8; ConnectTokenPrivate::SIZE]>,
+    /// An optional random nonce identifying the connection attempt, appended after the token data. <br>
+    /// This is not part of the standard netcode protocol: stock servers reject request packets that contain it.
+    pub connection_nonce: Option<u64>,
 }
 
 impl RequestPacket {
@@ -90,6 +95,17 @@ impl RequestPacket {
         expire_timestamp: u64,
         token_nonce: XNonce,
         token_data: [u8; ConnectTokenPrivate::SIZE],
+    ) -> Packet {
+        Self::create_with_nonce(protocol_id, expire_timestamp, token_nonce, token_data, None)
+    }
+
+    /// Create a connection request packet that includes a connection nonce, to correlate the connection attempt in logs
+    pub fn create_with_nonce(
+        protocol_id: u64,
+        expire_timestamp: u64,
+        token_nonce: XNonce,
+        token_data: [u8; ConnectTokenPrivate::SIZE],
+        connection_nonce: Option<u64>,
     ) -> Packet {
         Packet::Request(RequestPacket {
             version_info: *NETCODE_VERSION,
@@ -97,6 +113,7 @@ impl RequestPacket {
             expire_timestamp,
             token_nonce,
             token_data: Box::new(token_data),
+            connection_nonce,
         })
     }
     pub fn validate(&self, protocol_id: u64, current_timestamp: u64) -> Result<(), Error> {
@@ -137,6 +154,9 @@ impl Bytes for RequestPacket {
         writer.write_u64(self.expire_timestamp)?;
         writer.write_all(&self.token_nonce)?;
         writer.write_all(&self.token_data[..])?;
+        if let Some(connection_nonce) = self.connection_nonce {
+            writer.write_u64(connection_nonce)?;
+        }
         Ok(())
     }
 
@@ -151,12 +171,15 @@ impl Bytes for RequestPacket {
         let token_nonce = XNonce::from_slice(&nonce).to_owned();
         let mut token_data = [0; ConnectTokenPrivate::SIZE];
         reader.read_exact(&mut token_data)?;
+        // the packet only contains a connection nonce if the client opted in
+        let connection_nonce = reader.read_u64().ok();
         Ok(Self {
             version_info,
             protocol_id,
             expire_timestamp,
             token_nonce,
             token_data: Box::new(token_data),
+            connection_nonce,
         })
     }
 }
@@ -309,13 +332,30 @@ impl Bytes for ChallengePacket {
 pub struct ResponsePacket {
     pub sequence: u64,
     pub token: [u8; ChallengeToken::SIZE],
+    /// Whether the client accepts the rekey packets of the server.
+    ///
+    /// This is not part of the standard netcode protocol: the flag is an optional byte appended to the
+    /// response packet, which is only written if the client allows rekeying.
+    pub allow_rekey: bool,
 }
 
 impl ResponsePacket {
+    const ALLOW_REKEY: u8 = 1;
+
     pub fn create(sequence: u64, token_bytes: [u8; ChallengeToken::SIZE]) -> Packet {
+        Self::create_with_rekey(sequence, token_bytes, false)
+    }
+
+    /// Create a response packet that advertises whether the client allows rekeying
+    pub fn create_with_rekey(
+        sequence: u64,
+        token_bytes: [u8; ChallengeToken::SIZE],
+        allow_rekey: bool,
+    ) -> Packet {
         Packet::Response(ResponsePacket {
             sequence,
             token: token_bytes,
+            allow_rekey,
         })
     }
 }
@@ -325,24 +365,67 @@ impl Bytes for ResponsePacket {
     fn write_to(&self, writer: &mut impl WriteInteger) -> Result<(), Self::Error> {
         writer.write_u64(self.sequence)?;
         writer.write_all(&self.token)?;
+        if self.allow_rekey {
+            writer.write_u8(Self::ALLOW_REKEY)?;
+        }
         Ok(())
     }
 
+    /// The optional flag is read by [`Packet::read`], which knows the size of the packet
     fn read_from(reader: &mut impl ReadInteger) -> Result<Self, io::Error> {
         let sequence = reader.read_u64()?;
         let mut token = [0; ChallengeToken::SIZE];
         reader.read_exact(&mut token)?;
-        Ok(Self { sequence, token })
+        Ok(Self {
+            sequence,
+            token,
+            allow_rekey: false,
+        })
     }
 }
 
 pub struct KeepAlivePacket {
     pub client_id: ClientId,
+    /// The client's slot and the server's capacity and occupancy, if the server advertises them
+    pub occupancy: Option<KeepAliveOccupancy>,
+}
+
+/// The optional fields appended to the keep-alive packets by the server.
+///
+/// This is not part of the standard netcode protocol: stock keep-alives only contain the client id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepAliveOccupancy {
+    /// The index of the slot assigned to the client by the server
+    pub client_index: u32,
+    /// The maximum number of clients the server accepts
+    pub max_clients: u32,
+    /// The number of clients currently connected to the server
+    pub num_clients: u32,
 }
 
 impl KeepAlivePacket {
     pub fn create(client_id: ClientId) -> Packet {
-        Packet::KeepAlive(KeepAlivePacket { client_id })
+        Packet::KeepAlive(KeepAlivePacket {
+            client_id,
+            occupancy: None,
+        })
+    }
+
+    /// Create a keep-alive packet that advertises the client's slot, and the server's capacity and occupancy
+    pub fn create_with_occupancy(
+        client_id: ClientId,
+        client_index: u32,
+        max_clients: u32,
+        num_clients: u32,
+    ) -> Packet {
+        Packet::KeepAlive(KeepAlivePacket {
+            client_id,
+            occupancy: Some(KeepAliveOccupancy {
+                client_index,
+                max_clients,
+                num_clients,
+            }),
+        })
     }
 }
 
@@ -350,12 +433,21 @@ impl Bytes for KeepAlivePacket {
     type Error = io::Error;
     fn write_to(&self, writer: &mut impl WriteInteger) -> Result<(), Self::Error> {
         writer.write_u64(self.client_id)?;
+        if let Some(occupancy) = self.occupancy {
+            writer.write_u32(occupancy.client_index)?;
+            writer.write_u32(occupancy.max_clients)?;
+            writer.write_u32(occupancy.num_clients)?;
+        }
         Ok(())
     }
 
+    /// The optional occupancy is read by [`Packet::read`], which knows the size of the packet
     fn read_from(reader: &mut impl ReadInteger) -> Result<Self, io::Error> {
         let client_id = reader.read_u64()?;
-        Ok(Self { client_id })
+        Ok(Self {
+            client_id,
+            occupancy: None,
+        })
     }
 }
 
@@ -369,22 +461,112 @@ impl PayloadPacket {
     }
 }
 
-pub struct DisconnectPacket {}
+/// Why the server disconnected a client, sent in the disconnect packets.
+///
+/// This is not part of the standard netcode protocol: the code is an optional byte appended to the
+/// disconnect packet, which stock clients ignore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectCode {
+    /// The client was kicked from the server.
+    Kicked,
+    /// The server is shutting down.
+    Shutdown,
+    /// The client was banned from the server.
+    Banned,
+    /// The client was moved to another server.
+    Moved,
+    /// A code that is not known by this version of the crate.
+    Other(u8),
+}
+
+impl From<u8> for DisconnectCode {
+    fn from(code: u8) -> Self {
+        match code {
+            0 => DisconnectCode::Kicked,
+            1 => DisconnectCode::Shutdown,
+            2 => DisconnectCode::Banned,
+            3 => DisconnectCode::Moved,
+            code => DisconnectCode::Other(code),
+        }
+    }
+}
+
+impl From<DisconnectCode> for u8 {
+    fn from(code: DisconnectCode) -> Self {
+        match code {
+            DisconnectCode::Kicked => 0,
+            DisconnectCode::Shutdown => 1,
+            DisconnectCode::Banned => 2,
+            DisconnectCode::Moved => 3,
+            DisconnectCode::Other(code) => code,
+        }
+    }
+}
+
+pub struct DisconnectPacket {
+    pub code: Option<DisconnectCode>,
+}
 
 impl DisconnectPacket {
     pub fn create() -> Packet {
-        Packet::Disconnect(Self {})
+        Self::create_with_code(None)
+    }
+
+    /// Create a disconnect packet that tells the client why it is disconnected
+    pub fn create_with_code(code: Option<DisconnectCode>) -> Packet {
+        Packet::Disconnect(Self { code })
     }
 }
 
 impl Bytes for DisconnectPacket {
     type Error = io::Error;
-    fn write_to(&self, _writer: &mut impl WriteInteger) -> Result<(), Self::Error> {
+    fn write_to(&self, writer: &mut impl WriteInteger) -> Result<(), Self::Error> {
+        if let Some(code) = self.code {
+            writer.write_u8(code.into())?;
+        }
         Ok(())
     }
 
+    /// The optional code is read by [`Packet::read`], which knows the size of the packet
     fn read_from(_reader: &mut impl ReadInteger) -> Result<Self, io::Error> {
-        Ok(Self {})
+        Ok(Self { code: None })
+    }
+}
+
+/// Fresh session keys sent by the server to a client that allows rekeying.
+///
+/// This is not part of the standard netcode protocol.
+pub struct RekeyPacket {
+    pub client_to_server_key: Key,
+    pub server_to_client_key: Key,
+}
+
+impl RekeyPacket {
+    pub fn create(client_to_server_key: Key, server_to_client_key: Key) -> Packet {
+        Packet::Rekey(RekeyPacket {
+            client_to_server_key,
+            server_to_client_key,
+        })
+    }
+}
+
+impl Bytes for RekeyPacket {
+    type Error = io::Error;
+    fn write_to(&self, writer: &mut impl WriteInteger) -> Result<(), Self::Error> {
+        writer.write_all(&self.client_to_server_key)?;
+        writer.write_all(&self.server_to_client_key)?;
+        Ok(())
+    }
+
+    fn read_from(reader: &mut impl ReadInteger) -> Result<Self, io::Error> {
+        let mut client_to_server_key = Key::default();
+        reader.read_exact(&mut client_to_server_key)?;
+        let mut server_to_client_key = Key::default();
+        reader.read_exact(&mut server_to_client_key)?;
+        Ok(Self {
+            client_to_server_key,
+            server_to_client_key,
+        })
     }
 }
 
@@ -397,6 +579,7 @@ pub enum Packet {
     KeepAlive(KeepAlivePacket),
     Payload(PayloadPacket),
     Disconnect(DisconnectPacket),
+    Rekey(RekeyPacket),
 }
 
 impl core::fmt::Display for Packet {
@@ -409,6 +592,7 @@ impl core::fmt::Display for Packet {
             Packet::Disconnect(_) => write!(f, "disconnect packet"),
             Packet::Denied(_) => write!(f, "denied packet"),
             Packet::Challenge(_) => write!(f, "challenge packet"),
+            Packet::Rekey(_) => write!(f, "rekey packet"),
         }
     }
 }
@@ -423,6 +607,7 @@ impl Packet {
     pub const KEEP_ALIVE: PacketKind = 4;
     pub const PAYLOAD: PacketKind = 5;
     pub const DISCONNECT: PacketKind = 6;
+    pub const REKEY: PacketKind = 7;
     fn kind(&self) -> PacketKind {
         match self {
             Packet::Request(_) => Packet::REQUEST,
@@ -432,6 +617,7 @@ impl Packet {
             Packet::KeepAlive(_) => Packet::KEEP_ALIVE,
             Packet::Payload(_) => Packet::PAYLOAD,
             Packet::Disconnect(_) => Packet::DISCONNECT,
+            Packet::Rekey(_) => Packet::REKEY,
         }
     }
     fn set_prefix(&self, sequence: u64) -> u8 {
@@ -477,6 +663,7 @@ impl Packet {
             Packet::Response(pkt) => pkt.write_to(&mut cursor)?,
             Packet::KeepAlive(pkt) => pkt.write_to(&mut cursor)?,
             Packet::Disconnect(pkt) => pkt.write_to(&mut cursor)?,
+            Packet::Rekey(pkt) => pkt.write_to(&mut cursor)?,
             Packet::Payload(PayloadPacket { buf }) => cursor.write_all(buf)?,
             _ => unreachable!(), // Packet::Request variant is handled above
         }
@@ -494,6 +681,21 @@ impl Packet {
 
         Ok(encryption_end)
     }
+    /// Same as [`write`](Packet::write), but serializes the packet directly at the end of `writer`,
+    /// which avoids copying it from an intermediate buffer.
+    ///
+    /// If the packet can't be written, `writer` is truncated back to its length before the call.
+    pub fn write_into(
+        &self,
+        writer: &mut Writer,
+        sequence: u64,
+        packet_key: &Key,
+        protocol_id: u64,
+    ) -> Result<usize, NetcodeError> {
+        writer.write_with(MAX_PKT_BUF_SIZE, |out| {
+            self.write(out, sequence, packet_key, protocol_id)
+        })
+    }
     pub fn read(
         buf: RecvPayload, // buffer needs to be mutable to perform decryption in-place
         protocol_id: u64,
@@ -512,8 +714,12 @@ impl Packet {
         let mut cursor = io::Cursor::new(buf);
         let prefix_byte = cursor.read_u8()?;
         let (sequence_len, pkt_kind) = Packet::get_prefix(prefix_byte);
+        if pkt_kind > Packet::REKEY {
+            return Err(Error::InvalidType(pkt_kind).into());
+        }
         if allowed_packets & (1 << pkt_kind) == 0 {
             debug!("ignoring packet of type {}, not allowed", pkt_kind);
+            return Err(Error::NotAllowed(pkt_kind).into());
         }
         if prefix_byte == Packet::REQUEST {
             // connection request packet: first byte should be 0x00
@@ -538,18 +744,18 @@ impl Packet {
 
         let decryption_start = cursor.position() as usize;
 
-        // suffix starts at `decryption_start`
-        // this should not make a copy since we should only have one owner of the bytes
-        let mut suffix = BytesMut::from(cursor.into_inner().split_off(decryption_start));
+        // the packet is decrypted in place: this doesn't copy the bytes if we are their only owner.
+        // The whole buffer is converted before splitting it, since the split would share the allocation
+        let mut buf = BytesMut::from(cursor.into_inner());
         crypto::chacha_decrypt(
-            suffix.as_mut(),
+            &mut buf[decryption_start..],
             Some(&Packet::aead(protocol_id, prefix_byte)?),
             sequence,
             &key,
         )?;
 
-        // remove the last
-        let mut cursor = io::Cursor::new(suffix.freeze());
+        // the decrypted suffix, the payload of a payload packet is a view into the received buffer
+        let mut cursor = io::Cursor::new(buf.freeze().split_off(decryption_start));
 
         if let Some(replay_protection) = replay_protection
             && pkt_kind >= Packet::KEEP_ALIVE
@@ -561,9 +767,35 @@ impl Packet {
             Packet::REQUEST => Packet::Request(RequestPacket::read_from(&mut cursor)?),
             Packet::DENIED => Packet::Denied(DeniedPacket::read_from(&mut cursor)?),
             Packet::CHALLENGE => Packet::Challenge(ChallengePacket::read_from(&mut cursor)?),
-            Packet::RESPONSE => Packet::Response(ResponsePacket::read_from(&mut cursor)?),
-            Packet::KEEP_ALIVE => Packet::KeepAlive(KeepAlivePacket::read_from(&mut cursor)?),
-            Packet::DISCONNECT => Packet::Disconnect(DisconnectPacket::read_from(&mut cursor)?),
+            Packet::RESPONSE => {
+                let mut packet = ResponsePacket::read_from(&mut cursor)?;
+                // the packet only contains the flag if the client allows rekeying
+                if cursor.get_ref().len() - cursor.position() as usize > MAC_BYTES {
+                    packet.allow_rekey = cursor.read_u8()? & ResponsePacket::ALLOW_REKEY != 0;
+                }
+                Packet::Response(packet)
+            }
+            Packet::KEEP_ALIVE => {
+                let mut packet = KeepAlivePacket::read_from(&mut cursor)?;
+                // the packet only contains the occupancy if the server advertises it
+                if cursor.get_ref().len() - cursor.position() as usize > MAC_BYTES {
+                    packet.occupancy = Some(KeepAliveOccupancy {
+                        client_index: cursor.read_u32()?,
+                        max_clients: cursor.read_u32()?,
+                        num_clients: cursor.read_u32()?,
+                    });
+                }
+                Packet::KeepAlive(packet)
+            }
+            Packet::DISCONNECT => {
+                let mut packet = DisconnectPacket::read_from(&mut cursor)?;
+                // the optional code is the only byte before the MAC
+                if cursor.get_ref().len() - cursor.position() as usize > MAC_BYTES {
+                    packet.code = Some(cursor.read_u8()?.into());
+                }
+                Packet::Disconnect(packet)
+            }
+            Packet::REKEY => Packet::Rekey(RekeyPacket::read_from(&mut cursor)?),
             Packet::PAYLOAD => {
                 let mut buf = cursor.into_inner();
                 buf.truncate(buf.len() - MAC_BYTES);
@@ -585,7 +817,6 @@ mod tests {
 
     use alloc::vec::Vec;
     use chacha20poly1305::{AeadCore, XChaCha20Poly1305, aead::OsRng};
-    use lightyear_serde::writer::Writer;
     use std::dbg;
 
     use crate::{MAX_PACKET_SIZE, USER_DATA_BYTES, crypto::generate_key, token::AddressList};
@@ -643,6 +874,7 @@ mod tests {
             expire_timestamp,
             token_nonce: nonce,
             token_data: Box::new(token_data),
+            connection_nonce: Some(0xdead_beef),
         });
 
         let mut buf = Writer::from([0; MAX_PACKET_SIZE]);
@@ -669,6 +901,7 @@ mod tests {
         assert_eq!(req_pkt.protocol_id, protocol_id);
         assert_eq!(req_pkt.expire_timestamp, expire_timestamp);
         assert_eq!(req_pkt.token_nonce, nonce);
+        assert_eq!(req_pkt.connection_nonce, Some(0xdead_beef));
 
         let mut reader = io::Cursor::new(&req_pkt.token_data[..]);
         let connect_token_private = ConnectTokenPrivate::read_from(&mut reader).unwrap();
@@ -789,7 +1022,7 @@ mod tests {
         let client_id = 0x1234;
         let mut replay_protection = ReplayProtection::new();
 
-        let packet = Packet::KeepAlive(KeepAlivePacket { client_id });
+        let packet = KeepAlivePacket::create_with_occupancy(client_id, 3, 64, 47);
 
         let mut buf = Writer::from([0; MAX_PKT_BUF_SIZE]);
         let size = packet
@@ -811,25 +1044,121 @@ mod tests {
         };
 
         assert_eq!(keep_alive_pkt.client_id, client_id);
+        assert_eq!(
+            keep_alive_pkt.occupancy,
+            Some(KeepAliveOccupancy {
+                client_index: 3,
+                max_clients: 64,
+                num_clients: 47,
+            })
+        );
     }
 
     #[test]
-    pub fn disconnect_packet() {
+    fn stock_keep_alive_packet() {
+        let packet_key = generate_key();
+        let mut buf = Writer::from([0; MAX_PKT_BUF_SIZE]);
+        let size = KeepAlivePacket::create(0x1234)
+            .write(buf.as_mut(), 0, &packet_key, 0)
+            .unwrap();
+        // prefix, sequence, client id and MAC
+        assert_eq!(size, 1 + 1 + size_of::<ClientId>() + MAC_BYTES);
+
+        let packet = Packet::read(buf.split_to(size), 0, 0, packet_key, None, 0xff).unwrap();
+        let Packet::KeepAlive(keep_alive_pkt) = packet else {
+            panic!("wrong packet type");
+        };
+        assert_eq!(keep_alive_pkt.client_id, 0x1234);
+        assert_eq!(keep_alive_pkt.occupancy, None);
+    }
+
+    #[test]
+    fn response_packet_rekey_flag() {
+        // the MAC is random, so try several keys to make sure it is never read as the flag
+        for _ in 0..16 {
+            for allow_rekey in [false, true] {
+                let packet_key = generate_key();
+                let mut buf = Writer::from([0; MAX_PKT_BUF_SIZE]);
+                let size =
+                    ResponsePacket::create_with_rekey(3, [0; ChallengeToken::SIZE], allow_rekey)
+                        .write(buf.as_mut(), 0, &packet_key, 0)
+                        .unwrap();
+
+                let packet =
+                    Packet::read(buf.split_to(size), 0, 0, packet_key, None, 0xff).unwrap();
+                let Packet::Response(response_pkt) = packet else {
+                    panic!("wrong packet type");
+                };
+                assert_eq!(response_pkt.allow_rekey, allow_rekey);
+            }
+        }
+    }
+
+    #[test]
+    fn write_into_writer() {
+        let packet_key = generate_key();
+        let protocol_id = 0x1234_5678_9abc_def0;
+        let packet = PayloadPacket::create(SendPayload::from_static(b"hello"));
+
+        let mut buf = [0; MAX_PKT_BUF_SIZE];
+        let size = packet.write(&mut buf, 3, &packet_key, protocol_id).unwrap();
+
+        let mut writer = Writer::with_capacity(MAX_PKT_BUF_SIZE);
+        let written = packet
+            .write_into(&mut writer, 3, &packet_key, protocol_id)
+            .unwrap();
+        assert_eq!(written, size);
+        assert_eq!(&writer.split()[..], &buf[..size]);
+        // the packets can be written one after the other in the same writer
+        packet
+            .write_into(&mut writer, 3, &packet_key, protocol_id)
+            .unwrap();
+        assert_eq!(writer.len(), size);
+    }
+
+    #[test]
+    fn failed_write_into_is_rolled_back() {
+        let packet_key = generate_key();
+        let protocol_id = 0x1234_5678_9abc_def0;
+        let mut writer = Writer::with_capacity(MAX_PKT_BUF_SIZE);
+
+        let oversized =
+            PayloadPacket::create(SendPayload::from(alloc::vec![0xff; MAX_PKT_BUF_SIZE]));
+        assert!(
+            oversized
+                .write_into(&mut writer, 2, &packet_key, protocol_id)
+                .is_err()
+        );
+        assert!(writer.is_empty());
+
+        // the next packet is not prefixed with the bytes of the failed one
+        let packet = PayloadPacket::create(SendPayload::from_static(b"hello"));
+        let mut buf = [0; MAX_PKT_BUF_SIZE];
+        let size = packet.write(&mut buf, 3, &packet_key, protocol_id).unwrap();
+        packet
+            .write_into(&mut writer, 3, &packet_key, protocol_id)
+            .unwrap();
+        assert_eq!(&writer.split()[..], &buf[..size]);
+    }
+
+    #[test]
+    pub fn rekey_packet() {
         let packet_key = generate_key();
         let protocol_id = 0x1234_5678_9abc_def0;
         let sequence = 0u64;
+        let client_to_server_key = generate_key();
+        let server_to_client_key = generate_key();
         let mut replay_protection = ReplayProtection::new();
 
-        let packet = Packet::Disconnect(DisconnectPacket {});
+        let packet = RekeyPacket::create(client_to_server_key, server_to_client_key);
 
         let mut buf = Writer::from([0; MAX_PKT_BUF_SIZE]);
         let size = packet
             .write(buf.as_mut(), sequence, &packet_key, protocol_id)
             .unwrap();
 
-        let received = buf.split_to(size);
         let packet = Packet::read(
-            received,
+            buf.split_to(size),
             protocol_id,
             0,
             packet_key,
@@ -838,9 +1167,48 @@ mod tests {
         )
         .unwrap();
 
-        let Packet::Disconnect(_disconnect_pkt) = packet else {
+        let Packet::Rekey(rekey_pkt) = packet else {
             panic!("wrong packet type");
         };
+
+        assert_eq!(rekey_pkt.client_to_server_key, client_to_server_key);
+        assert_eq!(rekey_pkt.server_to_client_key, server_to_client_key);
+    }
+
+    #[test]
+    pub fn disconnect_packet() {
+        let packet_key = generate_key();
+        let protocol_id = 0x1234_5678_9abc_def0;
+        let mut replay_protection = ReplayProtection::new();
+
+        for (sequence, code) in [
+            (0u64, None),
+            (1, Some(DisconnectCode::Banned)),
+            (2, Some(DisconnectCode::Other(42))),
+        ] {
+            let packet = DisconnectPacket::create_with_code(code);
+
+            let mut buf = Writer::from([0; MAX_PKT_BUF_SIZE]);
+            let size = packet
+                .write(buf.as_mut(), sequence, &packet_key, protocol_id)
+                .unwrap();
+
+            let received = buf.split_to(size);
+            let packet = Packet::read(
+                received,
+                protocol_id,
+                0,
+                packet_key,
+                Some(&mut replay_protection),
+                0xff,
+            )
+            .unwrap();
+
+            let Packet::Disconnect(disconnect_pkt) = packet else {
+                panic!("wrong packet type");
+            };
+            assert_eq!(disconnect_pkt.code, code);
+        }
     }
 
     #[test]
@@ -874,4 +1242,25 @@ mod tests {
 
         assert_eq!(data_pkt.buf.len(), 100);
     }
+
+    #[test]
+    fn payload_packet_is_not_copied() {
+        let packet_key = generate_key();
+        let protocol_id = 0x1234_5678_9abc_def0;
+        let packet = PayloadPacket::create(SendPayload::from(vec![7u8; 1200]));
+
+        let mut buf = [0; MAX_PKT_BUF_SIZE];
+        let size = packet.write(&mut buf, 5, &packet_key, protocol_id).unwrap();
+        let received = RecvPayload::from(buf[..size].to_vec());
+        let received_range = received.as_ptr_range();
+
+        let Packet::Payload(data_pkt) =
+            Packet::read(received, protocol_id, 0, packet_key, None, 0xff).unwrap()
+        else {
+            panic!("wrong packet type");
+        };
+        assert_eq!(&data_pkt.buf[..], &[7u8; 1200][..]);
+        // the payload is decrypted in place, and points into the received buffer
+        assert!(received_range.contains(&data_pkt.buf.as_ptr()));
+    }
 }
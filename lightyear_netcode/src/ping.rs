@@ -0,0 +1,51 @@
+/*! Application-level ping/pong used to measure the round-trip time precisely.
+
+A ping is a regular payload packet made of the [`PING_PREFIX`] followed by a `u16` ping id (little-endian).
+A server configured with [`ServerConfig::echo_pings`](crate::ServerConfig::echo_pings) sends the ping back
+unchanged as soon as it receives it, and the client measures the time elapsed since it sent the ping.
+This is independent of the keep-alive cadence, so it still works when keep-alives are suppressed by active traffic.
+
+# Reserved prefix
+
+Application payloads starting with [`PING_PREFIX`] are echoed by the server, and can be consumed by the client if
+they match the id of the ping in flight. Make sure your payloads never start with these bytes when using pings.
+*/
+use bytes::{BufMut, BytesMut};
+use lightyear_link::SendPayload;
+
+/// The bytes at the start of every ping payload.
+pub const PING_PREFIX: &[u8; 4] = b"\xFFLYP";
+
+/// The size of a ping payload in bytes.
+pub const PING_SIZE: usize = PING_PREFIX.len() + size_of::<u16>();
+
+/// Creates the payload of the ping with the given id.
+pub(crate) fn write(id: u16) -> SendPayload {
+    let mut buf = BytesMut::with_capacity(PING_SIZE);
+    buf.put_slice(PING_PREFIX);
+    buf.put_u16_le(id);
+    buf.freeze()
+}
+
+/// Returns the id of the ping if `payload` is a ping.
+pub(crate) fn read(payload: &[u8]) -> Option<u16> {
+    if payload.len() != PING_SIZE || !payload.starts_with(PING_PREFIX) {
+        return None;
+    }
+    Some(u16::from_le_bytes([payload[4], payload[5]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_round_trip() {
+        let ping = write(0x1234);
+        assert_eq!(ping.len(), PING_SIZE);
+        assert_eq!(read(&ping), Some(0x1234));
+
+        assert_eq!(read(b"\xFFLYP"), None);
+        assert_eq!(read(b"hello!"), None);
+    }
+}
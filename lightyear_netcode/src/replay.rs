@@ -1,17 +1,25 @@
-const REPLAY_PROTECTION_BUFFER_SIZE: usize = 256;
+use alloc::{vec, vec::Vec};
+
+pub(crate) const REPLAY_PROTECTION_BUFFER_SIZE: usize = 256;
 const UNRECEIVED: u64 = u64::MAX;
 
 #[derive(Clone, Debug)]
 pub struct ReplayProtection {
     most_recent_sequence: u64,
-    received_packet: [u64; REPLAY_PROTECTION_BUFFER_SIZE],
+    received_packet: Vec<u64>,
 }
 
 impl ReplayProtection {
     pub fn new() -> Self {
+        Self::with_capacity(REPLAY_PROTECTION_BUFFER_SIZE)
+    }
+    /// Create a replay protection that keeps track of the last `capacity` sequence numbers.
+    ///
+    /// `capacity` should be a power of two.
+    pub fn with_capacity(capacity: usize) -> Self {
         Self {
             most_recent_sequence: 0,
-            received_packet: [UNRECEIVED; REPLAY_PROTECTION_BUFFER_SIZE],
+            received_packet: vec![UNRECEIVED; capacity],
         }
     }
     pub fn advance_sequence(&mut self, sequence: u64) {
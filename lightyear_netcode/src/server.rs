@@ -10,12 +10,15 @@ use super::{
     bytes::Bytes,
     crypto::{self, Key},
     error::{Error, Result},
+    obfuscation::ObfuscationKey,
     packet::{
-        ChallengePacket, DeniedPacket, DisconnectPacket, KeepAlivePacket, Packet, PayloadPacket,
-        RequestPacket, ResponsePacket,
+        ChallengePacket, DeniedPacket, DisconnectCode, DisconnectPacket, KeepAlivePacket, Packet,
+        PayloadPacket, RekeyPacket, RequestPacket, ResponsePacket,
     },
+    ping,
     replay::ReplayProtection,
     token::{ChallengeToken, ConnectToken, ConnectTokenBuilder, ConnectTokenPrivate},
+    transform::{PayloadPipeline, PayloadTransform, TransformOrder},
 };
 use crate::token::TOKEN_EXPIRE_SEC;
 use lightyear_connection::prelude::client::Connecting;
@@ -33,6 +36,9 @@ pub const MAX_CLIENTS: usize = 256;
 
 const CLIENT_TIMEOUT_SECS: i32 = 10;
 
+/// Number of rekey packets sent to a client before the server gives up on the rekey, if none of them is acknowledged.
+const REKEY_PACKETS: usize = 10;
+
 #[derive(Clone, Copy)]
 struct TokenEntry {
     time: f64,
@@ -84,6 +90,8 @@ struct Connection {
     confirmed: bool,
     connected: bool,
     client_id: ClientId,
+    // index of the slot of the client, advertised in the keep-alive packets
+    client_index: u32,
     entity: Entity,
     timeout: i32,
     last_access_time: f64,
@@ -93,6 +101,19 @@ struct Connection {
     receive_key: Key,
     sequence: u64,
     user_data: [u8; USER_DATA_BYTES],
+    // whether the client advertised that it allows rekeying in its challenge response
+    allow_rekey: bool,
+    pending_rekey: Option<PendingRekey>,
+}
+
+/// Session keys sent to a client in rekey packets, adopted by the server once the client acknowledges them
+/// by sending a packet encrypted with the new keys.
+#[derive(Debug, Clone)]
+struct PendingRekey {
+    send_key: Key,
+    receive_key: Key,
+    last_send_time: f64,
+    packets_left: usize,
 }
 
 impl Connection {
@@ -151,12 +172,15 @@ impl ConnectionCache {
             existing.receive_key = receive_key;
             existing.last_access_time = time;
             existing.user_data = user_data;
+            existing.allow_rekey = false;
+            existing.pending_rekey = None;
             return;
         }
         let conn = Connection {
             confirmed: false,
             connected: false,
             client_id,
+            client_index: self.free_client_index(),
             entity,
             timeout,
             last_access_time: time,
@@ -166,6 +190,8 @@ impl ConnectionCache {
             receive_key,
             sequence: 1 << 62,
             user_data,
+            allow_rekey: false,
+            pending_rekey: None,
         };
         self.clients.insert(client_id, conn);
         self.replay_protection
@@ -173,6 +199,12 @@ impl ConnectionCache {
 
         self.client_id_map.insert(entity, client_id);
     }
+    /// Lowest client index that is not used by another connection
+    fn free_client_index(&self) -> u32 {
+        (0..)
+            .find(|idx| self.clients.values().all(|conn| conn.client_index != *idx))
+            .expect("there are fewer connections than client indices")
+    }
     fn remove(&mut self, client_id: ClientId) {
         let Some(conn) = self.clients.get(&client_id) else {
             return;
@@ -224,6 +256,20 @@ impl ConnectionCache {
 pub type Callback<Ctx> = Box<dyn FnMut(ClientId, Entity, &mut Ctx) + Send + Sync + 'static>;
 pub type ConnectCallback<Ctx> =
     Box<dyn FnMut(ClientId, Entity, [u8; USER_DATA_BYTES], &mut Ctx) + Send + Sync + 'static>;
+pub type DisconnectCallback<Ctx> =
+    Box<dyn FnMut(ClientId, Entity, DisconnectReason, &mut Ctx) + Send + Sync + 'static>;
+
+/// Why a client was disconnected from the server, see [`ServerConfig::on_client_disconnect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DisconnectReason {
+    /// The server didn't receive any packet from the client within the timeout of its connect token.
+    TimedOut,
+    /// The client sent a disconnect packet.
+    ByClient,
+    /// The server disconnected the client with [`Server::disconnect`] or [`Server::disconnect_all`].
+    ByServer,
+}
 
 /// Configuration for a server.
 ///
@@ -231,6 +277,7 @@ pub type ConnectCallback<Ctx> =
 /// * `keep_alive_send_rate` - The rate at which keep-alive packets will be sent to clients.
 /// * `on_connect` - A callback that will be called when a client is connected to the server.
 /// * `on_disconnect` - A callback that will be called when a client is disconnected from the server.
+/// * `on_client_disconnect` - A callback that will be called with the reason when a client is disconnected from the server.
 ///
 /// # Example
 /// ```
@@ -255,9 +302,14 @@ pub struct ServerConfig<Ctx> {
     client_timeout_secs: i32,
     connection_request_handler: Arc<dyn ConnectionRequestHandler>,
     server_addr: SocketAddr,
+    wire_obfuscation: Option<ObfuscationKey>,
+    payload_pipeline: PayloadPipeline,
+    echo_pings: bool,
+    advertise_occupancy: bool,
     pub(crate) context: Ctx,
     on_connect: Option<ConnectCallback<Ctx>>,
     on_disconnect: Option<Callback<Ctx>>,
+    on_client_disconnect: Option<DisconnectCallback<Ctx>>,
 }
 
 impl Default for ServerConfig<()> {
@@ -269,9 +321,14 @@ impl Default for ServerConfig<()> {
             client_timeout_secs: CLIENT_TIMEOUT_SECS,
             connection_request_handler: Arc::new(DefaultConnectionRequestHandler),
             server_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            wire_obfuscation: None,
+            payload_pipeline: PayloadPipeline::default(),
+            echo_pings: false,
+            advertise_occupancy: false,
             context: (),
             on_connect: None,
             on_disconnect: None,
+            on_client_disconnect: None,
         }
     }
 }
@@ -290,9 +347,14 @@ impl<Ctx> ServerConfig<Ctx> {
             client_timeout_secs: CLIENT_TIMEOUT_SECS,
             connection_request_handler: Arc::new(DefaultConnectionRequestHandler),
             server_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            wire_obfuscation: None,
+            payload_pipeline: PayloadPipeline::default(),
+            echo_pings: false,
+            advertise_occupancy: false,
             context: ctx,
             on_connect: None,
             on_disconnect: None,
+            on_client_disconnect: None,
         }
     }
     /// Set the number of redundant disconnect packets that will be sent to a client when the server is disconnecting it. <br>
@@ -325,6 +387,45 @@ impl<Ctx> ServerConfig<Ctx> {
         self.server_addr = server_addr;
         self
     }
+    /// Set the key used to obfuscate the packets on the wire, or `None` to disable the obfuscation. <br>
+    /// This is not encryption (the packets are already encrypted), it only defeats trivial protocol fingerprinting.
+    /// The clients must be configured with the same key. See [`ObfuscationKey`].
+    pub fn wire_obfuscation(mut self, key: Option<ObfuscationKey>) -> Self {
+        self.wire_obfuscation = key;
+        self
+    }
+    /// Set the transform used to compress the payloads before they are encrypted. <br>
+    /// See the [`transform`](crate::transform) module for the ordering of the transforms and the risks of compression.
+    /// The client must use the matching transform.
+    pub fn compression(mut self, transform: impl PayloadTransform) -> Self {
+        self.payload_pipeline.compression = Some(Box::new(transform));
+        self
+    }
+    /// Set an application transform applied to the payloads before they are encrypted. <br>
+    /// The client must use the matching transform.
+    pub fn payload_transform(mut self, transform: impl PayloadTransform) -> Self {
+        self.payload_pipeline.app_transform = Some(Box::new(transform));
+        self
+    }
+    /// Set the order in which the compression and the application transforms are applied. <br>
+    /// The default is [`TransformOrder::CompressFirst`].
+    pub fn transform_order(mut self, order: TransformOrder) -> Self {
+        self.payload_pipeline.order = order;
+        self
+    }
+    /// Set to true to send the pings received from the clients back to them. <br>
+    /// See the [`ping`](crate::ping) module for more details. The default is false.
+    pub fn echo_pings(mut self, echo_pings: bool) -> Self {
+        self.echo_pings = echo_pings;
+        self
+    }
+    /// Set to true to append the client's slot, and the server's capacity and occupancy, to the keep-alive packets. <br>
+    /// Stock netcode clients reject these keep-alives, so only enable this if all the clients understand them.
+    /// The default is false.
+    pub fn advertise_occupancy(mut self, advertise_occupancy: bool) -> Self {
+        self.advertise_occupancy = advertise_occupancy;
+        self
+    }
     /// Provide a callback that will be called when a client is connected to the server. <br>
     /// The callback will be called with the client index, entity, user data from the connection token,
     /// and the context that was provided (provide a `None` context if you don't need one).
@@ -348,6 +449,18 @@ impl<Ctx> ServerConfig<Ctx> {
         self.on_disconnect = Some(Box::new(cb));
         self
     }
+    /// Provide a callback that will be called when a client is disconnected from the server. <br>
+    /// The callback will be called with the client id, entity, the [`DisconnectReason`]
+    /// and the context that was provided. It is called after the [`on_disconnect`](ServerConfig::on_disconnect) callback.
+    ///
+    /// The address of the client can be read from the `PeerAddr` component of the entity, if the IO provides it.
+    pub fn on_client_disconnect<F>(mut self, cb: F) -> Self
+    where
+        F: FnMut(ClientId, Entity, DisconnectReason, &mut Ctx) + Send + Sync + 'static,
+    {
+        self.on_client_disconnect = Some(Box::new(cb));
+        self
+    }
 }
 
 /// The `netcode` server.
@@ -357,6 +470,8 @@ impl<Ctx> ServerConfig<Ctx> {
 pub struct Server<Ctx = ()> {
     time: f64,
     private_key: Key,
+    // older private keys that are still accepted when decrypting connect tokens, newest first
+    previous_keys: Vec<Key>,
     sequence: u64,
     token_sequence: u64,
     challenge_sequence: u64,
@@ -370,9 +485,10 @@ pub struct Server<Ctx = ()> {
     send_queue: HashMap<Entity, Vec<SendPayload>>,
     // We use a Writer (wrapper around BytesMut) here because we will keep re-using the
     // same allocation for the bytes we send.
-    // 1. We create an array on the stack of size MAX_PACKET_SIZE
-    // 2. We copy the serialized array in the writer via `extend_from_size`
-    // 3. We split the bytes off, to recover the allocation
+    // 1. We serialize the packet at the end of the writer via `Packet::write_into`
+    // 2. We split the bytes off, to recover the allocation
+    // The writer is always empty between two sends: a packet that fails to be written is rolled back,
+    // so its partial bytes can't end up at the start of the next packet.
     writer: Writer,
     client_errors: Vec<Error>,
 }
@@ -385,6 +501,7 @@ impl Server {
         let server: Server<()> = Server {
             time: 0.0,
             private_key,
+            previous_keys: vec![],
             protocol_id,
             sequence: 1 << 23,
             token_sequence: 0,
@@ -400,6 +517,22 @@ impl Server {
         // info!("server started on {}", server.io.local_addr());
         Ok(server)
     }
+
+    /// Create a new server that accepts connect tokens generated with any of the provided private keys.
+    ///
+    /// The keys are ordered from newest to oldest: the first key is used to generate new connect tokens
+    /// via [`Server::token`], and the private data of incoming connection requests is decrypted
+    /// with each key in order. This lets the private key be rotated without invalidating the tokens
+    /// that were already handed out with the previous key.
+    ///
+    /// Returns [`Error::NoPrivateKey`] if `keys` is empty.
+    pub fn with_keys(protocol_id: u64, keys: Vec<Key>) -> Result<Self> {
+        let mut keys = keys.into_iter();
+        let private_key = keys.next().ok_or(Error::NoPrivateKey)?;
+        let mut server = Self::new(protocol_id, private_key)?;
+        server.previous_keys = keys.collect();
+        Ok(server)
+    }
 }
 
 impl<Ctx> Server<Ctx> {
@@ -422,6 +555,7 @@ impl<Ctx> Server<Ctx> {
         let server = Server {
             time: 0.0,
             private_key,
+            previous_keys: vec![],
             protocol_id,
             sequence: 1 << 23,
             token_sequence: 0,
@@ -455,10 +589,13 @@ impl<Ctx> Server<Ctx> {
             cb(client_id, entity, user_data, &mut self.cfg.context)
         }
     }
-    fn on_disconnect(&mut self, client_id: ClientId, entity: Entity) {
+    fn on_disconnect(&mut self, client_id: ClientId, entity: Entity, reason: DisconnectReason) {
         if let Some(cb) = self.cfg.on_disconnect.as_mut() {
             cb(client_id, entity, &mut self.cfg.context)
         }
+        if let Some(cb) = self.cfg.on_client_disconnect.as_mut() {
+            cb(client_id, entity, reason, &mut self.cfg.context)
+        }
     }
     fn handle_client_error(&mut self, error: Error) {
         self.client_errors.push(error);
@@ -500,7 +637,26 @@ impl<Ctx> Server<Ctx> {
                     self.conn_cache.find_by_entity(&entity).map(|c| c.client_id)
                 {
                     self.touch_client(client_id);
-                    Ok(Some(packet.buf))
+                    let payload = self.cfg.payload_pipeline.decode(packet.buf);
+                    if payload.is_none() {
+                        debug!(
+                            "server dropped payload packet from client {client_id} that failed to decode"
+                        );
+                    }
+                    if let Some(payload) = payload.as_ref()
+                        && self.cfg.echo_pings
+                        && ping::read(payload).is_some()
+                    {
+                        trace!("server echoing ping from client {client_id}");
+                        let pong = self.cfg.payload_pipeline.encode(payload.clone());
+                        self.send_netcode_to_client(
+                            PayloadPacket::create(pong),
+                            client_id,
+                            entity,
+                        )?;
+                        return Ok(None);
+                    }
+                    Ok(payload)
                 } else {
                     Ok(None)
                 }
@@ -508,7 +664,7 @@ impl<Ctx> Server<Ctx> {
             Packet::Disconnect(_) => {
                 if let Some(idx) = self.conn_cache.find_by_entity(&entity).map(|c| c.client_id) {
                     debug!("server disconnected client {idx}");
-                    self.on_disconnect(idx, entity);
+                    self.on_disconnect(idx, entity, DisconnectReason::ByClient);
                     self.conn_cache.remove(idx);
                 }
                 Ok(None)
@@ -517,9 +673,10 @@ impl<Ctx> Server<Ctx> {
         }
     }
     fn send_netcode_packet(&mut self, packet: Packet, key: Key, entity: Entity) -> Result<()> {
-        let mut buf = [0u8; MAX_PKT_BUF_SIZE];
-        let size = packet.write(&mut buf, self.sequence, &key, self.protocol_id)?;
-        self.writer.extend_from_slice(&buf[..size]);
+        packet.write_into(&mut self.writer, self.sequence, &key, self.protocol_id)?;
+        if let Some(key) = &self.cfg.wire_obfuscation {
+            key.apply(self.writer.as_mut());
+        }
         self.send_queue
             .entry(entity)
             .or_default()
@@ -528,9 +685,10 @@ impl<Ctx> Server<Ctx> {
         Ok(())
     }
     fn send_to_addr(&mut self, packet: Packet, key: Key, sender: &mut LinkSender) -> Result<()> {
-        let mut buf = [0u8; MAX_PKT_BUF_SIZE];
-        let size = packet.write(&mut buf, self.sequence, &key, self.protocol_id)?;
-        self.writer.extend_from_slice(&buf[..size]);
+        packet.write_into(&mut self.writer, self.sequence, &key, self.protocol_id)?;
+        if let Some(key) = &self.cfg.wire_obfuscation {
+            key.apply(self.writer.as_mut());
+        }
         sender.push(self.writer.split());
         self.sequence += 1;
         Ok(())
@@ -547,9 +705,15 @@ impl<Ctx> Server<Ctx> {
             .get_mut(&id)
             .ok_or(Error::ClientNotFound(id::PeerId::Netcode(id)))?;
 
-        let mut buf = [0u8; MAX_PKT_BUF_SIZE];
-        let size = packet.write(&mut buf, conn.sequence, &conn.send_key, self.protocol_id)?;
-        self.writer.extend_from_slice(&buf[..size]);
+        packet.write_into(
+            &mut self.writer,
+            conn.sequence,
+            &conn.send_key,
+            self.protocol_id,
+        )?;
+        if let Some(key) = &self.cfg.wire_obfuscation {
+            key.apply(self.writer.as_mut());
+        }
         sender.push(self.writer.split());
 
         conn.last_access_time = self.time;
@@ -570,9 +734,15 @@ impl<Ctx> Server<Ctx> {
             .get_mut(&id)
             .ok_or(Error::ClientNotFound(id::PeerId::Netcode(id)))?;
 
-        let mut buf = [0u8; MAX_PKT_BUF_SIZE];
-        let size = packet.write(&mut buf, conn.sequence, &conn.send_key, self.protocol_id)?;
-        self.writer.extend_from_slice(&buf[..size]);
+        packet.write_into(
+            &mut self.writer,
+            conn.sequence,
+            &conn.send_key,
+            self.protocol_id,
+        )?;
+        if let Some(key) = &self.cfg.wire_obfuscation {
+            key.apply(self.writer.as_mut());
+        }
         self.send_queue
             .entry(entity)
             .or_default()
@@ -589,7 +759,12 @@ impl<Ctx> Server<Ctx> {
         mut packet: RequestPacket,
         entity_mut: &mut EntityCommands,
     ) -> Result<()> {
-        trace!("Server received connection request packet");
+        match packet.connection_nonce {
+            Some(nonce) => {
+                debug!("server received connection request packet, nonce: {nonce:#018x}")
+            }
+            None => trace!("Server received connection request packet"),
+        }
         let mut reader = io::Cursor::new(&mut packet.token_data[..]);
         let token = ConnectTokenPrivate::read_from(&mut reader)?;
         let entity = entity_mut.id();
@@ -722,6 +897,7 @@ impl<Ctx> Server<Ctx> {
         let client = self.conn_cache.clients.get_mut(&id).unwrap();
 
         client.connect();
+        client.allow_rekey = packet.allow_rekey;
         client.last_send_time = self.time;
         client.last_receive_time = self.time;
         let user_data = client.user_data;
@@ -729,7 +905,7 @@ impl<Ctx> Server<Ctx> {
             "server accepted client {} with id {}",
             id, challenge_token.client_id
         );
-        self.send_netcode_to_client(KeepAlivePacket::create(id), id, entity)?;
+        self.send_netcode_to_client(self.keep_alive_packet(id), id, entity)?;
         self.on_connect(id, entity, user_data);
         Ok(())
     }
@@ -746,7 +922,7 @@ impl<Ctx> Server<Ctx> {
                 && client.last_receive_time + (client.timeout as f64) < self.time
             {
                 debug!("server timed out client {id}");
-                self.on_disconnect(id, entity);
+                self.on_disconnect(id, entity, DisconnectReason::TimedOut);
                 self.conn_cache.remove(id);
             }
         }
@@ -771,6 +947,22 @@ impl<Ctx> Server<Ctx> {
     //     Ok(())
     // }
 
+    /// Keep-alive packet for a given client, advertising its slot and the server's capacity and occupancy if enabled
+    fn keep_alive_packet(&self, id: ClientId) -> Packet {
+        if !self.cfg.advertise_occupancy {
+            return KeepAlivePacket::create(id);
+        }
+        KeepAlivePacket::create_with_occupancy(
+            id,
+            self.conn_cache
+                .clients
+                .get(&id)
+                .map_or(0, |conn| conn.client_index),
+            MAX_CLIENTS as u32,
+            self.num_connected_clients() as u32,
+        )
+    }
+
     /// Send keep-alives to a given client
     pub(crate) fn send_keepalives(&mut self, id: ClientId, sender: &mut LinkSender) -> Result<()> {
         let Some(client) = self.conn_cache.clients.get_mut(&id) else {
@@ -779,10 +971,21 @@ impl<Ctx> Server<Ctx> {
         if !client.is_connected() {
             return Ok(());
         }
-        if client.last_send_time + self.cfg.keep_alive_send_rate >= self.time {
+        let keep_alive_due = client.last_send_time + self.cfg.keep_alive_send_rate < self.time;
+        if let Some(rekey) = client.pending_rekey.as_mut()
+            && rekey.packets_left > 0
+            && rekey.last_send_time + self.cfg.keep_alive_send_rate < self.time
+        {
+            rekey.last_send_time = self.time;
+            rekey.packets_left -= 1;
+            let packet = RekeyPacket::create(rekey.receive_key, rekey.send_key);
+            self.send_to_client(packet, id, sender)?;
+            trace!("server sent rekey packet to client {id}");
+        }
+        if !keep_alive_due {
             return Ok(());
         }
-        self.send_to_client(KeepAlivePacket::create(id), id, sender)?;
+        self.send_to_client(self.keep_alive_packet(id), id, sender)?;
         trace!("server sent connection keep-alive packet to client {id}");
         Ok(())
     }
@@ -806,10 +1009,20 @@ impl<Ctx> Server<Ctx> {
             // Too small to be a packet
             return Ok(None);
         }
+        let buf = match &self.cfg.wire_obfuscation {
+            Some(key) => key.reverse(buf),
+            None => buf,
+        };
         let mut reader = io::Cursor::new(buf);
         let first_byte = reader.read_u8()?;
         let entity = entity_mut.id();
         // reader.rewind()?;
+        let retry = self
+            .conn_cache
+            .find_by_entity(&entity)
+            .filter(|_| first_byte != Packet::REQUEST)
+            .and_then(|c| c.pending_rekey.as_ref())
+            .map(|rekey| rekey.receive_key);
         let (key, replay_protection) = match self.conn_cache.find_by_entity(&entity) {
             // Regardless of whether an entry in the connection cache exists for the client or not,
             // if the packet is a connection request we need to use the server's private key to decrypt it.
@@ -832,18 +1045,85 @@ impl<Ctx> Server<Ctx> {
             }
         };
 
-        let packet = Packet::read(
-            reader.into_inner(),
+        let buf = reader.into_inner();
+        let retry = retry.map(|key| (key, buf.clone()));
+        let previous_keys_retry =
+            (first_byte == Packet::REQUEST && !self.previous_keys.is_empty()).then(|| buf.clone());
+        let result = Packet::read(
+            buf,
             self.protocol_id,
             now,
             key,
             replay_protection,
             Self::ALLOWED_PACKETS,
-        )?;
+        );
+        let result = match (result, previous_keys_retry) {
+            // the connect token might have been generated with a previous private key
+            (Err(e @ Error::Crypto(_)), Some(buf)) => self.read_with_previous_keys(buf, now, e),
+            (result, _) => result,
+        };
+        let packet = match (result, retry) {
+            // the client might have switched to the keys sent in the rekey packets
+            (Err(Error::Crypto(_)), Some((key, buf))) => {
+                self.read_with_pending_rekey(buf, now, key, entity)?
+            }
+            (result, _) => result?,
+        };
 
         self.process_packet(packet, entity_mut)
     }
 
+    /// Retries reading a connection request with each of the previous private keys,
+    /// in case the connect token was generated before the private key was rotated.
+    fn read_with_previous_keys(&self, buf: RecvPayload, now: u64, error: Error) -> Result<Packet> {
+        for key in &self.previous_keys {
+            match Packet::read(
+                buf.clone(),
+                self.protocol_id,
+                now,
+                *key,
+                None,
+                Self::ALLOWED_PACKETS,
+            ) {
+                Err(Error::Crypto(_)) => continue,
+                result => return result,
+            }
+        }
+        Err(error)
+    }
+
+    /// Reads a packet encrypted with the keys sent to the client in rekey packets,
+    /// and switches to these keys if it succeeds
+    fn read_with_pending_rekey(
+        &mut self,
+        buf: RecvPayload,
+        now: u64,
+        key: Key,
+        entity: Entity,
+    ) -> Result<Packet> {
+        let client_id = self
+            .conn_cache
+            .find_by_entity(&entity)
+            .map(|c| c.client_id)
+            .ok_or(Error::Ignored(entity))?;
+        let packet = Packet::read(
+            buf,
+            self.protocol_id,
+            now,
+            key,
+            self.conn_cache.replay_protection.get_mut(&client_id),
+            Self::ALLOWED_PACKETS,
+        )?;
+        if let Some(conn) = self.conn_cache.mut_by_id(client_id)
+            && let Some(rekey) = conn.pending_rekey.take()
+        {
+            debug!("server switched to the new session keys of client {client_id}");
+            conn.send_key = rekey.send_key;
+            conn.receive_key = rekey.receive_key;
+        }
+        Ok(packet)
+    }
+
     fn recv_packets(
         &mut self,
         receiver: &mut LinkReceiver,
@@ -894,6 +1174,7 @@ impl<Ctx> Server<Ctx> {
         client_id: ClientId,
         sender: &mut LinkSender,
     ) -> Result<()> {
+        let buf = self.cfg.payload_pipeline.encode(buf);
         if buf.len() > MAX_PACKET_SIZE {
             return Err(Error::SizeMismatch(MAX_PACKET_SIZE, buf.len()));
         }
@@ -908,12 +1189,45 @@ impl<Ctx> Server<Ctx> {
         }
         if !conn.is_confirmed() {
             // send a keep-alive packet to the client to confirm the connection
-            self.send_to_client(KeepAlivePacket::create(client_id), client_id, sender)?;
+            self.send_to_client(self.keep_alive_packet(client_id), client_id, sender)?;
         }
         let packet = PayloadPacket::create(buf);
         self.send_to_client(packet, client_id, sender)
     }
 
+    /// Rotates the session keys of a connected client.
+    ///
+    /// Fresh keys are sent to the client in rekey packets (encrypted with the current keys), at the keep-alive rate.
+    /// The server switches to the new keys once it receives a packet encrypted with them from the client.
+    /// The client advertises whether it [allows rekeying](crate::client::ClientConfig::allow_rekey) during the handshake:
+    /// returns [`Error::RekeyNotAllowed`] for the clients that didn't, which keep using the keys from their connect token.
+    /// If the rekey packets are never acknowledged, the server stops sending them after a few attempts
+    /// and keeps using the current keys.
+    ///
+    /// This is not part of the standard netcode protocol. Does nothing if a rekey is already pending for this client.
+    pub fn rekey(&mut self, client_id: ClientId) -> Result<()> {
+        let Some(conn) = self.conn_cache.mut_by_id(client_id) else {
+            return Err(Error::ClientNotFound(id::PeerId::Netcode(client_id)));
+        };
+        if !conn.is_connected() {
+            return Err(Error::ClientNotConnected(id::PeerId::Netcode(client_id)));
+        }
+        if !conn.allow_rekey {
+            return Err(Error::RekeyNotAllowed(id::PeerId::Netcode(client_id)));
+        }
+        if conn.pending_rekey.is_some() {
+            debug!("a rekey is already pending for client {client_id}");
+            return Ok(());
+        }
+        conn.pending_rekey = Some(PendingRekey {
+            send_key: crypto::try_generate_key()?,
+            receive_key: crypto::try_generate_key()?,
+            last_send_time: f64::NEG_INFINITY,
+            packets_left: REKEY_PACKETS,
+        });
+        Ok(())
+    }
+
     /// Sends a packet to all connected clients.
     ///
     /// The provided buffer must be smaller than [`MAX_PACKET_SIZE`].
@@ -969,6 +1283,28 @@ impl<Ctx> Server<Ctx> {
     ///
     /// The server will send a number of redundant disconnect packets to the client, and then remove its connection info.
     pub fn disconnect(&mut self, client_id: ClientId, sender: &mut LinkSender) -> Result<()> {
+        self.send_disconnect(client_id, None, sender)
+    }
+
+    /// Disconnects a client, telling it why with a [`DisconnectCode`].
+    ///
+    /// The code is available on the client with [`Client::last_disconnect_reason`](crate::client::Client::last_disconnect_reason).
+    /// This is not part of the standard netcode protocol: stock clients ignore the code.
+    pub fn disconnect_with_code(
+        &mut self,
+        client_id: ClientId,
+        code: DisconnectCode,
+        sender: &mut LinkSender,
+    ) -> Result<()> {
+        self.send_disconnect(client_id, Some(code), sender)
+    }
+
+    fn send_disconnect(
+        &mut self,
+        client_id: ClientId,
+        code: Option<DisconnectCode>,
+        sender: &mut LinkSender,
+    ) -> Result<()> {
         let Some(conn) = self.conn_cache.clients.get_mut(&client_id) else {
             return Ok(());
         };
@@ -977,11 +1313,11 @@ impl<Ctx> Server<Ctx> {
         }
         let entity = conn.entity;
         debug!("server disconnecting client {client_id}");
-        self.on_disconnect(client_id, entity);
+        self.on_disconnect(client_id, entity, DisconnectReason::ByServer);
         for _ in 0..self.cfg.num_disconnect_packets {
             // we do not use ? here because we want to continue even if the send fails
             let _ = self
-                .send_to_client(DisconnectPacket::create(), client_id, sender)
+                .send_to_client(DisconnectPacket::create_with_code(code), client_id, sender)
                 .inspect_err(|e| {
                     error!("server failed to send disconnect packet: {e}");
                 });
@@ -1098,6 +1434,29 @@ mod tests {
         // parameter is correctly threaded through.
     }
 
+    #[test]
+    fn on_client_disconnect_receives_timeout_reason() {
+        let cfg = ServerConfig::with_context(Vec::new()).on_client_disconnect(
+            |client_id, _entity, reason, disconnects: &mut Vec<(ClientId, DisconnectReason)>| {
+                disconnects.push((client_id, reason));
+            },
+        );
+        let mut server =
+            Server::with_config(0x1122334455667788, crate::crypto::generate_key(), cfg).unwrap();
+        server.conn_cache.add(
+            7,
+            Entity::PLACEHOLDER,
+            1,
+            [0; 32],
+            [0; 32],
+            [0; USER_DATA_BYTES],
+        );
+        server.conn_cache.clients.get_mut(&7).unwrap().connect();
+
+        server.update_state(2.0);
+        assert_eq!(server.cfg.context, [(7, DisconnectReason::TimedOut)]);
+    }
+
     #[test]
     fn connection_stores_user_data() {
         // Verify that Connection struct correctly stores user_data
@@ -1107,6 +1466,7 @@ mod tests {
             confirmed: false,
             connected: false,
             client_id: 123,
+            client_index: 0,
             entity: Entity::PLACEHOLDER,
             timeout: 10,
             last_access_time: 0.0,
@@ -1116,8 +1476,52 @@ mod tests {
             receive_key: [0; 32],
             sequence: 0,
             user_data,
+            allow_rekey: false,
+            pending_rekey: None,
         };
 
         assert_eq!(conn.user_data, user_data);
     }
+    #[test]
+    fn with_keys_accepts_tokens_from_previous_keys() {
+        let protocol_id = 0x1122334455667788;
+        let key_a = crypto::generate_key();
+        let key_b = crypto::generate_key();
+        assert!(matches!(
+            Server::with_keys(protocol_id, vec![]),
+            Err(Error::NoPrivateKey)
+        ));
+
+        // mint a token with the old key, then rotate to the new key
+        let token = ConnectToken::build("127.0.0.1:5000", protocol_id, 1, key_a)
+            .generate()
+            .unwrap();
+        let server = Server::with_keys(protocol_id, vec![key_b, key_a]).unwrap();
+        let request = RequestPacket::create(
+            protocol_id,
+            token.expire_timestamp,
+            token.nonce,
+            token.private_data,
+        );
+        let mut buf = [0u8; MAX_PKT_BUF_SIZE];
+        let size = request.write(&mut buf, 0, &key_b, protocol_id).unwrap();
+        let buf = RecvPayload::copy_from_slice(&buf[..size]);
+        let now = crate::utils::now().unwrap();
+
+        // the newest key cannot decrypt the token
+        let result = Packet::read(
+            buf.clone(),
+            protocol_id,
+            now,
+            key_b,
+            None,
+            Server::<()>::ALLOWED_PACKETS,
+        );
+        let Err(error @ Error::Crypto(_)) = result else {
+            panic!("expected a decryption error");
+        };
+        // but the previous key can
+        let packet = server.read_with_previous_keys(buf, now, error).unwrap();
+        assert!(matches!(packet, Packet::Request(_)));
+    }
 }
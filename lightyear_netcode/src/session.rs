@@ -0,0 +1,226 @@
+/*! Record every raw packet exchanged by a [`Client`] and replay the session against a fresh client.
+
+This is a debugging tool to reproduce bugs observed in the field:
+1. Tap a [`ClientConfig`] with a [`SessionRecorder`] to write every inbound and outbound packet,
+   with its timestamp, to a file.
+2. Load the file with a [`SessionReplayer`], and feed the recorded inbound packets to a new client
+   created from the same connect token, to reproduce the same state transitions.
+
+# Format
+
+The recording starts with the [`MAGIC`] bytes and the unix time (in seconds, `u64`) at which it started.
+Each packet is then written as:
+* the time (in seconds, `f64`) since the start of the recording
+* the [`Direction`] (`u8`)
+* the length of the packet (`u32`), followed by the packet bytes
+
+All integers are little-endian.
+*/
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+
+use lightyear_link::{LinkReceiver, RecvPayload};
+use tracing::error;
+
+use crate::client::{Client, ClientConfig, ClientState};
+use crate::utils;
+
+/// The bytes at the start of a session recording.
+pub const MAGIC: &[u8; 8] = b"LYNCREC1";
+
+/// Whether a recorded packet was received or sent by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Direction {
+    Inbound = 0,
+    Outbound = 1,
+}
+
+/// A packet in a session recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionRecord {
+    /// The time (in seconds) since the start of the recording.
+    pub time: f64,
+    pub direction: Direction,
+    /// The raw bytes of the packet, as they were on the wire.
+    pub bytes: Vec<u8>,
+}
+
+/// Writes every raw packet exchanged by a client to `W`.
+///
+/// See the [module-level documentation](self) for more details.
+pub struct SessionRecorder<W> {
+    writer: W,
+    start: utils::Instant,
+}
+
+impl<W: Write> SessionRecorder<W> {
+    /// Creates a recorder, writing the header of the recording to `writer`.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let start_unix = utils::now().map_err(|e| io::Error::other(alloc::format!("{e}")))?;
+        writer.write_all(MAGIC)?;
+        writer.write_all(&start_unix.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            start: utils::Instant::now(),
+        })
+    }
+
+    /// Records a packet, timestamped with the time elapsed since the recorder was created.
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let time = self.start.elapsed().as_secs_f64();
+        self.writer.write_all(&time.to_le_bytes())?;
+        self.writer.write_all(&[direction as u8])?;
+        self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.writer.write_all(bytes)
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send + 'static> SessionRecorder<W> {
+    /// Installs the `on_send_raw` and `on_recv_raw` callbacks of `cfg` to record every packet in `recorder`.
+    ///
+    /// This replaces any raw callbacks that were previously set on `cfg`.
+    pub fn tap<Ctx>(recorder: Arc<Mutex<Self>>, cfg: ClientConfig<Ctx>) -> ClientConfig<Ctx> {
+        let inbound = recorder.clone();
+        cfg.on_recv_raw(move |bytes, _| {
+            Self::record_shared(&inbound, Direction::Inbound, bytes);
+        })
+        .on_send_raw(move |bytes, _| {
+            Self::record_shared(&recorder, Direction::Outbound, bytes);
+        })
+    }
+
+    fn record_shared(recorder: &Mutex<Self>, direction: Direction, bytes: &[u8]) {
+        let Ok(mut recorder) = recorder.lock() else {
+            return;
+        };
+        if let Err(e) = recorder.record(direction, bytes) {
+            error!("failed to record {direction:?} packet: {e}");
+        }
+    }
+}
+
+/// Replays a session recorded with a [`SessionRecorder`].
+///
+/// See the [module-level documentation](self) for more details.
+pub struct SessionReplayer {
+    start_unix: u64,
+    records: Vec<SessionRecord>,
+}
+
+impl SessionReplayer {
+    /// Reads a recording from `reader`.
+    pub fn read(mut reader: impl Read) -> io::Result<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a session recording",
+            ));
+        }
+        let start_unix = u64::from_le_bytes(read_array(&mut reader)?);
+        let mut records = Vec::new();
+        loop {
+            let time = match read_array(&mut reader) {
+                Ok(time) => f64::from_le_bytes(time),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let direction = match read_array::<1>(&mut reader)? {
+                [0] => Direction::Inbound,
+                [1] => Direction::Outbound,
+                [d] => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        alloc::format!("invalid direction {d}"),
+                    ));
+                }
+            };
+            let len = u32::from_le_bytes(read_array(&mut reader)?) as usize;
+            let mut bytes = vec![0u8; len];
+            reader.read_exact(&mut bytes)?;
+            records.push(SessionRecord {
+                time,
+                direction,
+                bytes,
+            });
+        }
+        Ok(Self {
+            start_unix,
+            records,
+        })
+    }
+
+    /// The recorded packets, in order.
+    pub fn records(&self) -> &[SessionRecord] {
+        &self.records
+    }
+
+    /// A time source returning the unix time at which the session was recorded.
+    ///
+    /// Provide it to [`ClientConfig::time_source`] for the client used to replay the session.
+    pub fn time_source(&self) -> impl Fn() -> crate::Result<u64> + Send + Sync + 'static {
+        let start_unix = self.start_unix;
+        move || Ok(start_unix)
+    }
+
+    /// Feeds the recorded inbound packets to `client`, advancing its time to match the recording.
+    ///
+    /// The client should be created from the same connect token as the recorded one, and
+    /// [`connect`](Client::connect) should be called before replaying.
+    /// Returns the state of the client after each inbound packet.
+    pub fn replay<Ctx>(&self, client: &mut Client<Ctx>) -> crate::Result<Vec<ClientState>> {
+        let mut receiver = LinkReceiver::default();
+        let mut states = Vec::new();
+        let mut last_time = 0.0;
+        for record in self
+            .records
+            .iter()
+            .filter(|r| r.direction == Direction::Inbound)
+        {
+            receiver.push_raw(RecvPayload::copy_from_slice(&record.bytes));
+            states.push(client.try_update(record.time - last_time, &mut receiver)?);
+            last_time = record.time;
+        }
+        Ok(states)
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recording_round_trip() {
+        let mut recorder = SessionRecorder::new(Vec::new()).unwrap();
+        recorder.record(Direction::Outbound, &[0, 1, 2]).unwrap();
+        recorder.record(Direction::Inbound, &[3]).unwrap();
+        let bytes = recorder.into_inner();
+
+        let replayer = SessionReplayer::read(&bytes[..]).unwrap();
+        let records = replayer.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Outbound);
+        assert_eq!(records[0].bytes, [0, 1, 2]);
+        assert_eq!(records[1].direction, Direction::Inbound);
+        assert_eq!(records[1].bytes, [3]);
+        assert!(records[0].time <= records[1].time);
+
+        assert!(SessionReplayer::read(&b"NOTMAGIC"[..]).is_err());
+    }
+}
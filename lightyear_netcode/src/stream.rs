@@ -0,0 +1,70 @@
+/*! Logical payload streams with independent sequence spaces.
+
+Every payload sent with [`Client::send_on_stream`](crate::client::Client::send_on_stream) is prefixed with a
+[`StreamHeader`] containing the stream id and a sequence number that is incremented separately for each stream.
+This lets reliability and ordering layers built on top of netcode operate per-stream, without head-of-line
+blocking between e.g. high-rate telemetry and low-rate control messages.
+
+The receiving side splits the header from the payload with [`StreamHeader::split`].
+*/
+use bytes::{BufMut, BytesMut};
+use lightyear_link::{RecvPayload, SendPayload};
+
+/// The identifier of a logical payload stream.
+pub type StreamId = u8;
+
+/// The header prefixed to every payload sent on a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamHeader {
+    pub stream_id: StreamId,
+    /// The sequence number of the payload within its stream. Wraps around on overflow.
+    pub sequence: u16,
+}
+
+impl StreamHeader {
+    /// The size of the header in bytes.
+    pub const SIZE: usize = 3;
+
+    /// Prefixes `payload` with the header.
+    pub(crate) fn prepend(&self, payload: &[u8]) -> SendPayload {
+        let mut buf = BytesMut::with_capacity(Self::SIZE + payload.len());
+        buf.put_u8(self.stream_id);
+        buf.put_u16_le(self.sequence);
+        buf.put_slice(payload);
+        buf.freeze()
+    }
+
+    /// Splits the header from a payload received on a stream.
+    ///
+    /// Returns `None` if the payload is too small to contain a header.
+    pub fn split(payload: RecvPayload) -> Option<(StreamHeader, RecvPayload)> {
+        if payload.len() < Self::SIZE {
+            return None;
+        }
+        let header = StreamHeader {
+            stream_id: payload[0],
+            sequence: u16::from_le_bytes([payload[1], payload[2]]),
+        };
+        Some((header, payload.slice(Self::SIZE..)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let header = StreamHeader {
+            stream_id: 3,
+            sequence: 0x1234,
+        };
+        let payload = header.prepend(b"hello");
+        assert_eq!(payload.len(), StreamHeader::SIZE + 5);
+        let (read, payload) = StreamHeader::split(payload).unwrap();
+        assert_eq!(read, header);
+        assert_eq!(&payload[..], b"hello");
+
+        assert!(StreamHeader::split(RecvPayload::from_static(&[1, 2])).is_none());
+    }
+}
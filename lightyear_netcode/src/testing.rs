@@ -0,0 +1,256 @@
+//! In-memory harness to drive a netcode [`Client`] and [`Server`] against each other in tests,
+//! without sockets or a bevy `App`.
+//!
+//! # Example
+//! ```
+//! # use lightyear_netcode::{generate_key, client::Client, Server};
+//! # use lightyear_netcode::testing::{LinkPair, step};
+//! let mut server = Server::new(0, generate_key()).unwrap();
+//! let token = server
+//!     .token(1, "127.0.0.1:5000".parse().unwrap())
+//!     .generate()
+//!     .unwrap();
+//! let mut client = Client::new(&token.try_into_bytes().unwrap()).unwrap();
+//! let mut links = LinkPair::new();
+//!
+//! client.connect();
+//! step(&mut client, &mut server, &mut links, 60).unwrap();
+//! assert!(client.is_connected());
+//! ```
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use bevy_ecs::{
+    entity::Entity,
+    system::Commands,
+    world::{CommandQueue, World},
+};
+use lightyear_link::{Link, RecvPayload, SendPayload};
+
+use crate::client::Client;
+use crate::error::Result;
+use crate::server::Server;
+
+/// The fixed time (in seconds) by which the client and the server are advanced at every tick of [`step`].
+pub const TICK_DELTA: f64 = 1.0 / 60.0;
+
+/// A duplex in-memory connection between a [`Client`] and a [`Server`].
+///
+/// The packets buffered in the [`LinkSender`](lightyear_link::LinkSender) of one side are delivered
+/// to the [`LinkReceiver`](lightyear_link::LinkReceiver) of the other side, without loss or delay.
+pub struct LinkPair {
+    /// The link of the client. Payloads for the server should be sent with `client.send`.
+    pub client: Link,
+    /// The link of the client entity on the server. Payloads for the client should be sent with `server.send`.
+    pub server: Link,
+    /// The payloads that the server received from the client
+    pub server_payloads: VecDeque<RecvPayload>,
+    world: World,
+    entity: Entity,
+}
+
+impl Default for LinkPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkPair {
+    pub fn new() -> Self {
+        let mut world = World::new();
+        let entity = world.spawn_empty().id();
+        Self {
+            client: Link::new(None),
+            server: Link::new(None),
+            server_payloads: VecDeque::new(),
+            world,
+            entity,
+        }
+    }
+
+    /// The entity that represents the client on the server
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    /// The world in which the server inserts the components of the client entity
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    /// Delivers the packets buffered in each sender to the receiver on the other side.
+    pub fn ferry(&mut self) {
+        self.client
+            .send
+            .drain()
+            .for_each(|packet| self.server.recv.push_raw(packet));
+        self.server
+            .send
+            .drain()
+            .for_each(|packet| self.client.recv.push_raw(packet));
+    }
+}
+
+/// Advances the client and the server by `ticks` ticks of [`TICK_DELTA`] seconds.
+///
+/// At every tick, the client processes the packets received from the server and sends its packets,
+/// then the server does the same. The packets are exchanged through `links` in between.
+///
+/// The payloads received by the client can be read with [`Client::pop_payload`], and the payloads received
+/// by the server are stored in [`LinkPair::server_payloads`].
+///
+/// Returns an error if the client fails to send or receive packets.
+/// Errors caused by the packets of the client on the server are logged and ignored.
+pub fn step<C, S>(
+    client: &mut Client<C>,
+    server: &mut Server<S>,
+    links: &mut LinkPair,
+    ticks: usize,
+) -> Result<()> {
+    for _ in 0..ticks {
+        let inbound: Vec<RecvPayload> = links.client.recv.drain().collect();
+        client.step(
+            TICK_DELTA,
+            &mut inbound.iter().map(|packet| &packet[..]),
+            &mut |packet| links.client.send.push(SendPayload::copy_from_slice(packet)),
+        )?;
+        links.ferry();
+
+        server.update_state(TICK_DELTA);
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, &links.world);
+        let mut entity_mut = commands.entity(links.entity);
+        for error in server.receive(&mut links.server, &mut entity_mut)? {
+            error.log();
+        }
+        queue.apply(&mut links.world);
+        links.server_payloads.extend(links.server.recv.drain());
+
+        let connected = server
+            .connected_client_ids()
+            .find(|id| server.client_entity(*id) == Some(links.entity));
+        if let Some(client_id) = connected {
+            server.send_keepalives(client_id, &mut links.server.send)?;
+        }
+        server.send_netcode_packets(links.entity, &mut links.server.send);
+        links.ferry();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientConfig, ClientState};
+    use crate::crypto::generate_key;
+    use crate::error::Error;
+    use crate::server::{MAX_CLIENTS, ServerConfig};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    fn connect() -> (Client, Server, LinkPair) {
+        connect_with_config(ClientConfig::default())
+    }
+
+    fn connect_with_config(cfg: ClientConfig<()>) -> (Client, Server, LinkPair) {
+        connect_with_configs(cfg, ServerConfig::default())
+    }
+
+    fn connect_with_configs(
+        cfg: ClientConfig<()>,
+        server_cfg: ServerConfig<()>,
+    ) -> (Client, Server, LinkPair) {
+        let mut server = Server::with_config(0, generate_key(), server_cfg).unwrap();
+        let token = server
+            .token(1, "127.0.0.1:5000".parse().unwrap())
+            .generate()
+            .unwrap();
+        let mut client = Client::with_config(&token.try_into_bytes().unwrap(), cfg).unwrap();
+        let mut links = LinkPair::new();
+        client.connect();
+        step(&mut client, &mut server, &mut links, 60).unwrap();
+        (client, server, links)
+    }
+
+    #[test]
+    fn handshake() {
+        let (client, server, links) = connect();
+        assert_eq!(client.state(), ClientState::Connected);
+        assert_eq!(server.num_connected_clients(), 1);
+        assert_eq!(server.client_entity(1), Some(links.entity()));
+    }
+
+    #[test]
+    fn payloads_are_exchanged() {
+        let (mut client, mut server, mut links) = connect();
+        client
+            .send(SendPayload::from_static(b"ping"), &mut links.client.send)
+            .unwrap();
+        server
+            .send(SendPayload::from_static(b"pong"), 1, &mut links.server.send)
+            .unwrap();
+        // the payload of the server reaches the client on the next tick
+        step(&mut client, &mut server, &mut links, 2).unwrap();
+        assert_eq!(
+            links.server_payloads.pop_front().as_deref(),
+            Some(&b"ping"[..])
+        );
+        assert_eq!(client.pop_payload().as_deref(), Some(&b"pong"[..]));
+    }
+
+    #[test]
+    fn occupancy_is_opt_in() {
+        let (client, _, _) = connect();
+        assert_eq!(client.max_clients(), None);
+
+        let server_cfg = ServerConfig::default().advertise_occupancy(true);
+        let (client, _, _) = connect_with_configs(ClientConfig::default(), server_cfg);
+        assert_eq!(client.max_clients(), Some(MAX_CLIENTS as u32));
+        assert_eq!(client.client_index(), Some(0));
+        assert_eq!(client.current_clients(), Some(1));
+    }
+
+    #[test]
+    fn disconnect() {
+        let (mut client, mut server, mut links) = connect();
+        client.disconnect().unwrap();
+        step(&mut client, &mut server, &mut links, 1).unwrap();
+        assert_eq!(client.state(), ClientState::Disconnected);
+        assert_eq!(server.num_connected_clients(), 0);
+    }
+
+    #[test]
+    fn rekey_requires_the_client_to_allow_it() {
+        let (_, mut server, _) = connect();
+        assert!(matches!(server.rekey(1), Err(Error::RekeyNotAllowed(_))));
+    }
+
+    #[test]
+    fn rekey() {
+        let num_rekeys = Arc::new(AtomicU32::new(0));
+        let counter = num_rekeys.clone();
+        let cfg = ClientConfig::default()
+            .allow_rekey(true)
+            .on_rekey(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+        let (mut client, mut server, mut links) = connect_with_config(cfg);
+        server.rekey(1).unwrap();
+        step(&mut client, &mut server, &mut links, 60).unwrap();
+        assert_eq!(num_rekeys.load(Ordering::Relaxed), 1);
+
+        // traffic continues with the new keys
+        client
+            .send(SendPayload::from_static(b"ping"), &mut links.client.send)
+            .unwrap();
+        server
+            .send(SendPayload::from_static(b"pong"), 1, &mut links.server.send)
+            .unwrap();
+        step(&mut client, &mut server, &mut links, 2).unwrap();
+        assert_eq!(
+            links.server_payloads.pop_front().as_deref(),
+            Some(&b"ping"[..])
+        );
+        assert_eq!(client.pop_payload().as_deref(), Some(&b"pong"[..]));
+        assert_eq!(server.num_connected_clients(), 1);
+    }
+}
@@ -8,6 +8,7 @@ use super::{
 };
 use alloc::borrow::ToOwned;
 use alloc::format;
+use alloc::vec::Vec;
 use chacha20poly1305::{AeadCore, XChaCha20Poly1305, XNonce, aead::OsRng};
 use core::mem::size_of;
 use core::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
@@ -43,16 +44,25 @@ pub struct AddressList {
 impl AddressList {
     const IPV4: u8 = 1;
     const IPV6: u8 = 2;
+    /// Creates a list of server addresses.
+    ///
+    /// Returns an [`InvalidTokenError::AddressListLength`] error if there are no addresses,
+    /// or more than the 32 addresses that fit in a connect token.
     pub fn new(addrs: impl utils::ToSocketAddrs) -> Result<Self, Error> {
         let mut server_addresses = FreeList::new();
 
         for (i, addr) in addrs.to_socket_addrs()?.enumerate() {
             if i >= MAX_SERVERS_PER_CONNECT {
-                break;
+                return Err(Error::InvalidToken(InvalidTokenError::AddressListLength(
+                    addrs.to_socket_addrs()?.count() as u32,
+                )));
             }
 
             server_addresses.insert(addr);
         }
+        if server_addresses.len() == 0 {
+            return Err(Error::InvalidToken(InvalidTokenError::AddressListLength(0)));
+        }
 
         Ok(AddressList {
             addrs: server_addresses,
@@ -318,10 +328,53 @@ pub struct ConnectToken {
 }
 
 impl ConnectToken {
+    /// The timestamp when the token was created, in seconds since the unix epoch.
+    pub fn create_timestamp(&self) -> u64 {
+        self.create_timestamp
+    }
     /// The timestamp when the token expires, in seconds since the unix epoch.
     pub fn expire_timestamp(&self) -> u64 {
         self.expire_timestamp
     }
+    /// The time in seconds after which a connection is timed out if no packets are received.
+    /// A negative value means no timeout.
+    pub fn timeout_seconds(&self) -> i32 {
+        self.timeout_seconds
+    }
+    /// Returns true if the token is expired at `unix_secs` (in seconds since the unix epoch).
+    ///
+    /// This can be used to check that a token is still valid before creating a client with it,
+    /// which would otherwise transition to `ConnectTokenExpired` right away.
+    pub fn is_expired_at(&self, unix_secs: u64) -> bool {
+        unix_secs >= self.expire_timestamp
+    }
+    /// Returns the public metadata of the token, without the keys and the encrypted private data.
+    pub fn info(&self) -> ConnectTokenInfo {
+        ConnectTokenInfo {
+            server_addresses: self.server_addresses.iter().map(|(_, addr)| addr).collect(),
+            create_timestamp: self.create_timestamp,
+            expire_timestamp: self.expire_timestamp,
+            timeout_seconds: self.timeout_seconds,
+        }
+    }
+}
+
+/// The public metadata of a [`ConnectToken`], returned by [`ConnectToken::info`].
+///
+/// Unlike the token itself, this doesn't contain any key, so it can be logged or persisted
+/// (e.g. with the `serde` feature) to remember the last connection. It can't be used to connect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectTokenInfo {
+    /// The public addresses of the servers, in the order in which they are tried.
+    pub server_addresses: Vec<SocketAddr>,
+    /// The timestamp when the token was created, in seconds since the unix epoch.
+    pub create_timestamp: u64,
+    /// The timestamp when the token expires, in seconds since the unix epoch.
+    pub expire_timestamp: u64,
+    /// The time in seconds after which a connection is timed out if no packets are received.
+    /// A negative value means no timeout.
+    pub timeout_seconds: i32,
 }
 
 /// A builder that can be used to generate a connect token.
@@ -365,10 +418,25 @@ impl<A: utils::ToSocketAddrs> ConnectTokenBuilder<A> {
         self
     }
     /// Sets the user data that will be added to the token, this can be any data you want.
+    ///
+    /// The user data is encrypted with the private key, so only the server can read it
+    /// (it is passed to the `on_connect` callback of the server): it can carry claims
+    /// such as a username or a matchmaking ticket that the server trusts.
     pub fn user_data(mut self, user_data: [u8; USER_DATA_BYTES]) -> Self {
         self.user_data = user_data;
         self
     }
+    /// Sets the user data that will be added to the token from a slice, padded with zeros.
+    ///
+    /// Returns an error if the slice is longer than [`USER_DATA_BYTES`].
+    pub fn user_data_from_slice(mut self, user_data: &[u8]) -> Result<Self, Error> {
+        if user_data.len() > USER_DATA_BYTES {
+            return Err(Error::SizeMismatch(USER_DATA_BYTES, user_data.len()));
+        }
+        self.user_data = [0; USER_DATA_BYTES];
+        self.user_data[..user_data.len()].copy_from_slice(user_data);
+        Ok(self)
+    }
     /// Sets the **internal** server addresses in the private data of the token. <br>
     /// If this field is not set, the **public** server addresses provided when creating the builder will be used instead.
     ///
@@ -714,6 +782,9 @@ mod tests {
             connect_token.expire_timestamp,
             connect_token.create_timestamp + 6
         );
+        assert_eq!(connect_token.timeout_seconds(), 5);
+        assert!(!connect_token.is_expired_at(connect_token.create_timestamp() + 5));
+        assert!(connect_token.is_expired_at(connect_token.expire_timestamp()));
         connect_token
             .server_addresses
             .iter()
@@ -721,5 +792,64 @@ mod tests {
             .for_each(|((_, have), expected)| {
                 assert_eq!(have, expected);
             });
+
+        let info = connect_token.info();
+        assert_eq!(
+            info.server_addresses,
+            ["127.0.0.1:12345".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(info.expire_timestamp, connect_token.expire_timestamp());
+        assert_eq!(info.timeout_seconds, 5);
+    }
+
+    #[test]
+    fn address_list_length() {
+        let no_addresses: &[SocketAddr] = &[];
+        assert!(matches!(
+            AddressList::new(no_addresses),
+            Err(Error::InvalidToken(InvalidTokenError::AddressListLength(0)))
+        ));
+        assert!(matches!(
+            ConnectToken::build(no_addresses, 1, 4, [0x42; PRIVATE_KEY_BYTES]).generate(),
+            Err(Error::InvalidToken(InvalidTokenError::AddressListLength(0)))
+        ));
+
+        let addresses: Vec<SocketAddr> = (0..33)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        assert!(matches!(
+            AddressList::new(&addresses[..]),
+            Err(Error::InvalidToken(InvalidTokenError::AddressListLength(
+                33
+            )))
+        ));
+        assert_eq!(AddressList::new(&addresses[..32]).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn connect_token_builder_user_data_from_slice() {
+        let private_key = [0x42; PRIVATE_KEY_BYTES];
+        let builder = ConnectToken::build("127.0.0.1:12345", 1, 4, private_key);
+        assert!(matches!(
+            builder.user_data_from_slice(&[0; USER_DATA_BYTES + 1]),
+            Err(Error::SizeMismatch(USER_DATA_BYTES, _))
+        ));
+
+        let connect_token = ConnectToken::build("127.0.0.1:12345", 1, 4, private_key)
+            .user_data_from_slice(b"player")
+            .unwrap()
+            .generate()
+            .unwrap();
+        let mut private_data = connect_token.private_data;
+        let private_token = ConnectTokenPrivate::decrypt(
+            &mut private_data,
+            1,
+            connect_token.expire_timestamp,
+            connect_token.nonce,
+            &private_key,
+        )
+        .unwrap();
+        assert_eq!(&private_token.user_data[..6], b"player");
+        assert!(private_token.user_data[6..].iter().all(|&b| b == 0));
     }
 }
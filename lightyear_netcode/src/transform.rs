@@ -0,0 +1,219 @@
+/*! Reversible transforms applied to the payloads before they are encrypted by netcode.
+
+The ordering of the transforms is enforced by the crate. On send, a payload goes through:
+
+1. the compression transform
+2. the application transform
+3. the netcode encryption
+
+and the steps are reversed on receive. Compressing after encryption would be useless (encrypted
+bytes are not compressible), so encryption always comes last.
+
+# Compression oracles
+
+Compressing a payload before encrypting it leaks information through the size of the encrypted packet.
+If an attacker can influence part of the plaintext (e.g. a chat message) that is compressed together
+with a secret, they can recover the secret by observing how the packet sizes change
+(see the CRIME and BREACH attacks on TLS/HTTP compression).
+Only compress payloads that don't mix attacker-controlled data with secrets.
+*/
+use alloc::boxed::Box;
+#[cfg(feature = "lz4")]
+use alloc::vec::Vec;
+use lightyear_link::{RecvPayload, SendPayload};
+
+/// A reversible transform applied to the payloads before they are encrypted by netcode.
+pub trait PayloadTransform: Send + Sync + 'static {
+    /// Transforms a payload before it is sent.
+    fn encode(&self, payload: SendPayload) -> SendPayload;
+    /// Reverses the transform on a received payload. Returns `None` if the payload is invalid.
+    fn decode(&self, payload: RecvPayload) -> Option<RecvPayload>;
+}
+
+/// The order in which the compression and the application transforms are applied on send.
+///
+/// They are applied in the reverse order on receive. Netcode encryption is always applied last.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransformOrder {
+    /// `compress -> app_transform -> encrypt`.
+    /// This is the default, since the application transform might produce incompressible bytes.
+    #[default]
+    CompressFirst,
+    /// `app_transform -> compress -> encrypt`.
+    TransformFirst,
+}
+
+/// A compression transform that runs each payload through the LZ4 block codec.
+///
+/// Every payload is compressed independently, so it works over unordered and unreliable transports.
+/// A one-byte header indicates whether the payload was compressed: payloads that don't shrink
+/// (small or incompressible ones) are sent raw, at the cost of that single byte.
+/// The size limit of [`MAX_PACKET_SIZE`](crate::MAX_PACKET_SIZE) applies to the encoded payload.
+///
+/// Payloads that would decompress to more than [`Lz4Compression::MAX_DECOMPRESSED_LEN`] bytes are rejected.
+#[cfg(feature = "lz4")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Lz4Compression;
+
+#[cfg(feature = "lz4")]
+impl Lz4Compression {
+    /// The maximum size of a decompressed payload.
+    pub const MAX_DECOMPRESSED_LEN: usize = 64 * 1024;
+    const RAW: u8 = 0;
+    const COMPRESSED: u8 = 1;
+}
+
+#[cfg(feature = "lz4")]
+impl PayloadTransform for Lz4Compression {
+    fn encode(&self, payload: SendPayload) -> SendPayload {
+        let compressed = lz4_flex::block::compress_prepend_size(&payload);
+        let mut bytes = Vec::with_capacity(1 + payload.len().min(compressed.len()));
+        if compressed.len() < payload.len() {
+            bytes.push(Self::COMPRESSED);
+            bytes.extend_from_slice(&compressed);
+        } else {
+            bytes.push(Self::RAW);
+            bytes.extend_from_slice(&payload);
+        }
+        SendPayload::from(bytes)
+    }
+
+    fn decode(&self, payload: RecvPayload) -> Option<RecvPayload> {
+        match *payload.first()? {
+            Self::RAW => Some(payload.slice(1..)),
+            Self::COMPRESSED => {
+                // the decompressed size is prepended as a little-endian u32
+                let size = payload.get(1..5)?;
+                let size = u32::from_le_bytes(size.try_into().ok()?) as usize;
+                if size > Self::MAX_DECOMPRESSED_LEN {
+                    return None;
+                }
+                lz4_flex::block::decompress(&payload[5..], size)
+                    .ok()
+                    .map(RecvPayload::from)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The transforms applied to the payloads, in the configured order.
+#[derive(Default)]
+pub(crate) struct PayloadPipeline {
+    pub(crate) compression: Option<Box<dyn PayloadTransform>>,
+    pub(crate) app_transform: Option<Box<dyn PayloadTransform>>,
+    pub(crate) order: TransformOrder,
+}
+
+impl PayloadPipeline {
+    fn stages(&self) -> [Option<&dyn PayloadTransform>; 2] {
+        let compression = self.compression.as_deref();
+        let app_transform = self.app_transform.as_deref();
+        match self.order {
+            TransformOrder::CompressFirst => [compression, app_transform],
+            TransformOrder::TransformFirst => [app_transform, compression],
+        }
+    }
+
+    /// Applies the transforms to a payload that is about to be encrypted.
+    pub(crate) fn encode(&self, payload: SendPayload) -> SendPayload {
+        self.stages()
+            .into_iter()
+            .flatten()
+            .fold(payload, |payload, stage| stage.encode(payload))
+    }
+
+    /// Reverses the transforms on a payload that was just decrypted.
+    pub(crate) fn decode(&self, payload: RecvPayload) -> Option<RecvPayload> {
+        self.stages()
+            .into_iter()
+            .rev()
+            .flatten()
+            .try_fold(payload, |payload, stage| stage.decode(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Appends a marker byte on encode, and checks that it is the last byte on decode
+    struct Marker(u8);
+
+    impl PayloadTransform for Marker {
+        fn encode(&self, payload: SendPayload) -> SendPayload {
+            let mut bytes = Vec::from(&payload[..]);
+            bytes.push(self.0);
+            SendPayload::from(bytes)
+        }
+
+        fn decode(&self, payload: RecvPayload) -> Option<RecvPayload> {
+            let (&last, rest) = payload.split_last()?;
+            (last == self.0).then(|| payload.slice(..rest.len()))
+        }
+    }
+
+    #[test]
+    fn pipeline_round_trip() {
+        let pipeline = PayloadPipeline {
+            compression: Some(Box::new(Marker(1))),
+            app_transform: Some(Box::new(Marker(2))),
+            order: TransformOrder::CompressFirst,
+        };
+        let encoded = pipeline.encode(SendPayload::from_static(b"hi"));
+        assert_eq!(&encoded[..], b"hi\x01\x02");
+        assert_eq!(pipeline.decode(encoded).as_deref(), Some(&b"hi"[..]));
+
+        let pipeline = PayloadPipeline {
+            order: TransformOrder::TransformFirst,
+            ..pipeline
+        };
+        let encoded = pipeline.encode(SendPayload::from_static(b"hi"));
+        assert_eq!(&encoded[..], b"hi\x02\x01");
+        assert_eq!(pipeline.decode(encoded).as_deref(), Some(&b"hi"[..]));
+
+        // decoding in the wrong order fails
+        assert!(
+            pipeline
+                .decode(RecvPayload::from_static(b"hi\x01\x02"))
+                .is_none()
+        );
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_round_trip() {
+        let lz4 = Lz4Compression;
+
+        // highly compressible payloads are compressed
+        let payload = SendPayload::from(alloc::vec![7u8; 1000]);
+        let encoded = lz4.encode(payload.clone());
+        assert_eq!(encoded[0], Lz4Compression::COMPRESSED);
+        assert!(encoded.len() < payload.len());
+        assert_eq!(lz4.decode(encoded), Some(payload));
+
+        // random payloads are sent raw, with a single byte of overhead
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let random: Vec<u8> = (0..1000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let payload = SendPayload::from(random);
+        let encoded = lz4.encode(payload.clone());
+        assert_eq!(encoded[0], Lz4Compression::RAW);
+        assert_eq!(encoded.len(), payload.len() + 1);
+        assert_eq!(lz4.decode(encoded), Some(payload));
+
+        // unknown headers and oversized decompressed lengths are rejected
+        assert!(lz4.decode(RecvPayload::from_static(&[2, 0])).is_none());
+        assert!(
+            lz4.decode(RecvPayload::from_static(&[1, 0xff, 0xff, 0xff, 0xff, 0]))
+                .is_none()
+        );
+    }
+}
@@ -1,6 +1,10 @@
 #[cfg(all(feature = "std", target_arch = "wasm32"))]
+pub(crate) use web_time::Instant;
+#[cfg(all(feature = "std", target_arch = "wasm32"))]
 use web_time::SystemTime;
 
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub(crate) use std::time::Instant;
 #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 use std::time::SystemTime;
 
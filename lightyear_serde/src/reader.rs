@@ -214,6 +214,29 @@ pub(crate) mod no_std {
     }
 }
 
+impl Reader {
+    /// Reads the next frame written with [`Writer::write_framed`](crate::writer::Writer::write_framed).
+    ///
+    /// Returns `None` without advancing the reader if the remaining bytes don't contain a complete frame.
+    /// When reading from a stream, the incomplete frame can be recovered with [`split`](Reader::split)
+    /// and prepended to the bytes received next.
+    pub fn read_framed(&mut self) -> Option<Bytes> {
+        let start = self.position();
+        let first = *self.as_ref().get(start as usize)?;
+        if self.remaining() < varint_parse_len(first) {
+            return None;
+        }
+        let len = self.read_varint().ok()?;
+        match usize::try_from(len) {
+            Ok(len) if len <= self.remaining() => Some(self.split_len(len)),
+            _ => {
+                self.set_position(start);
+                None
+            }
+        }
+    }
+}
+
 pub trait ReadInteger: Read {
     #[inline]
     fn read_u8(&mut self) -> Result<u8> {
@@ -306,11 +329,35 @@ impl<T: Read + Seek> ReadVarInt for T {}
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::writer::WriteInteger;
+    use crate::writer::{WriteInteger, Writer};
     use no_std_io2::io;
 
     use alloc::vec;
 
+    #[test]
+    fn read_framed_across_buffers() {
+        let mut writer = Writer::default();
+        writer.write_framed(b"first").unwrap();
+        writer.write_framed(&[7; 100]).unwrap();
+        writer.write_framed(b"last").unwrap();
+        let bytes = writer.split();
+
+        // split the stream in the middle of the length of the second frame
+        let (head, tail) = bytes.split_at(7);
+        let mut reader = Reader::from(Bytes::copy_from_slice(head));
+        assert_eq!(reader.read_framed().as_deref(), Some(&b"first"[..]));
+        assert_eq!(reader.read_framed(), None);
+        assert_eq!(reader.read_framed(), None);
+
+        let mut buf = reader.split().to_vec();
+        buf.extend_from_slice(tail);
+        let mut reader = Reader::from(buf);
+        assert_eq!(reader.read_framed().as_deref(), Some(&[7; 100][..]));
+        assert_eq!(reader.read_framed().as_deref(), Some(&b"last"[..]));
+        assert_eq!(reader.read_framed(), None);
+        assert!(!reader.has_remaining());
+    }
+
     #[test]
     fn test_read_integer() {
         let mut writer = vec![];
@@ -91,6 +91,32 @@ pub(crate) mod std {
             self.0.get_mut().extend_from_slice(extend)
         }
 
+        /// Writes directly into the buffer with `f`, without going through an intermediate buffer.
+        ///
+        /// `f` receives a zeroed slice of `max_len` bytes at the end of the buffer, and returns the number of bytes
+        /// that it wrote. The rest of the slice is discarded, as well as the whole slice if `f` returns an error.
+        pub fn write_with<E>(
+            &mut self,
+            max_len: usize,
+            f: impl FnOnce(&mut [u8]) -> core::result::Result<usize, E>,
+        ) -> core::result::Result<usize, E> {
+            let buf = self.0.get_mut();
+            let start = buf.len();
+            buf.resize(start + max_len, 0);
+            let result = f(&mut buf[start..]);
+            let len = *result.as_ref().unwrap_or(&0);
+            buf.truncate(start + len.min(max_len));
+            result
+        }
+
+        /// Shortens the buffer to `len` bytes, discarding the rest. Has no effect if `len` is greater
+        /// than the current length.
+        ///
+        /// This can be used to roll back a partial write, by truncating to the length before the write.
+        pub fn truncate(&mut self, len: usize) {
+            self.0.get_mut().truncate(len)
+        }
+
         /// Splits the buffer into two at the given index.
         ///
         /// Afterwards `self` contains elements `[at, len)`, and the returned `BytesMut`
@@ -208,6 +234,40 @@ pub(crate) mod no_std {
             self.0.extend_from_slice(extend)
         }
 
+        /// Writes directly into the buffer with `f`, without going through an intermediate buffer.
+        ///
+        /// `f` receives a zeroed slice of `max_len` bytes at the end of the buffer, and returns the number of bytes
+        /// that it wrote. The rest of the slice is discarded, as well as the whole slice if `f` returns an error.
+        pub fn write_with<E>(
+            &mut self,
+            max_len: usize,
+            f: impl FnOnce(&mut [u8]) -> core::result::Result<usize, E>,
+        ) -> core::result::Result<usize, E> {
+            let buf = &mut self.0;
+            let start = buf.len();
+            buf.resize(start + max_len, 0);
+            let result = f(&mut buf[start..]);
+            let len = *result.as_ref().unwrap_or(&0);
+            buf.truncate(start + len.min(max_len));
+            result
+        }
+
+        /// Shortens the buffer to `len` bytes, discarding the rest. Has no effect if `len` is greater
+        /// than the current length.
+        ///
+        /// This can be used to roll back a partial write, by truncating to the length before the write.
+        pub fn truncate(&mut self, len: usize) {
+            self.0.truncate(len)
+        }
+
+        /// Splits the buffer into two at the given index.
+        ///
+        /// Afterwards `self` contains elements `[at, len)`, and the returned `BytesMut`
+        /// contains elements `[0, at)`.
+        pub fn split_to(&mut self, at: usize) -> Bytes {
+            self.0.split_to(at).freeze()
+        }
+
         // TODO: normally there is no need to reset, because once all the messages that have been split
         //  are dropped, the writer will move the current data to the front of the buffer to reuse memory
         //  All the split bytes messages are dropped at Send for unreliable senders, but NOT for reliable
@@ -249,6 +309,16 @@ impl Default for Writer {
     }
 }
 
+impl Writer {
+    /// Writes `payload` prefixed with its length as a varint, so that the frame boundaries can be
+    /// recovered from a stream of bytes (e.g. over TCP) with [`Reader::read_framed`](crate::reader::Reader::read_framed).
+    pub fn write_framed(&mut self, payload: &[u8]) -> core::result::Result<(), SerializationError> {
+        self.write_varint(payload.len() as u64)?;
+        self.extend_from_slice(payload);
+        Ok(())
+    }
+}
+
 pub trait WriteInteger: Write {
     #[inline]
     fn write_u8(&mut self, n: u8) -> Result<()> {
@@ -319,3 +389,38 @@ pub trait WriteInteger: Write {
 }
 
 impl<T: Write> WriteInteger for T {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_write_with_is_rolled_back() {
+        let mut writer = Writer::with_capacity(16);
+        writer.extend_from_slice(b"ab");
+        let result = writer.write_with(8, |out| {
+            out[..4].copy_from_slice(b"junk");
+            Err::<usize, ()>(())
+        });
+        assert!(result.is_err());
+        assert_eq!(&writer.split()[..], b"ab");
+
+        writer.extend_from_slice(b"abcd");
+        writer.truncate(1);
+        writer.truncate(3);
+        assert_eq!(&writer.split_to(1)[..], b"a");
+        assert!(writer.is_empty());
+    }
+
+    #[test]
+    fn write_framed() {
+        let mut writer = Writer::default();
+        writer.write_framed(b"hello").unwrap();
+        writer.write_framed(&[]).unwrap();
+        writer.write_framed(&[7; 100]).unwrap();
+        let bytes = writer.split();
+        // 1-byte length for the short frames, 2-byte length for the frame of 100 bytes
+        assert_eq!(bytes.len(), 1 + 5 + 1 + 2 + 100);
+        assert_eq!(&bytes[..6], b"\x05hello");
+    }
+}
@@ -47,7 +47,8 @@ pub(crate) const MTU: usize = 1472;
 /// This component is added to an entity with a `Link` component to enable
 /// sending and receiving data over UDP.
 /// The user must also add a `LocalAddr` component to specify the local socket address
-/// that will be bound.
+/// that will be bound. Once the socket is bound, the `LocalAddr` is replaced with the actual bound
+/// address (e.g. with the port assigned by the OS when binding to port 0).
 #[derive(Component)]
 #[require(Link)]
 // TODO: add LocalAddr using Construct
@@ -65,6 +66,13 @@ impl Default for UdpIo {
     }
 }
 
+impl UdpIo {
+    /// Returns the address the socket is bound to, or `None` if the socket is not bound.
+    pub fn local_addr(&self) -> Option<core::net::SocketAddr> {
+        self.socket.as_ref()?.local_addr().ok()
+    }
+}
+
 /// Errors related to the client connection
 #[derive(thiserror::Error, Debug)]
 pub enum UdpError {
@@ -93,10 +101,13 @@ impl UdpPlugin {
         if let Ok((mut udp_io, local_addr)) = query.get_mut(trigger.entity) {
             let local_addr = local_addr.ok_or(UdpError::LocalAddrMissing)?.0;
             let socket = UdpSocket::bind(local_addr)?;
-            info!("UDP socket bound to {}", local_addr);
+            let bound_addr = socket.local_addr()?;
+            info!("UDP socket bound to {}", bound_addr);
             socket.set_nonblocking(true)?;
             udp_io.socket = Some(socket);
-            commands.entity(trigger.entity).insert(Linked);
+            commands
+                .entity(trigger.entity)
+                .insert((LocalAddr(bound_addr), Linked));
         }
         Ok(())
     }
@@ -28,6 +28,7 @@ pub(crate) const MTU: usize = 1472;
 /// Component to start a UdpServer.
 ///
 /// The [`LocalAddr`] component is required to specify the local SocketAddr to bind.
+/// Once the socket is bound, it is replaced with the actual bound address.
 #[derive(Component)]
 #[require(Server)]
 pub struct ServerUdpIo {
@@ -76,11 +77,14 @@ impl ServerUdpPlugin {
     ) -> Result {
         if let Ok((mut udp_io, local_addr)) = query.get_mut(trigger.entity) {
             let local_addr = local_addr.ok_or(UdpError::LocalAddrMissing)?.0;
-            info!("Server UDP socket bound to {}", local_addr);
             let socket = std::net::UdpSocket::bind(local_addr)?;
+            let bound_addr = socket.local_addr()?;
+            info!("Server UDP socket bound to {}", bound_addr);
             socket.set_nonblocking(true)?;
             udp_io.socket = Some(socket);
-            commands.entity(trigger.entity).insert(Linked);
+            commands
+                .entity(trigger.entity)
+                .insert((LocalAddr(bound_addr), Linked));
         }
         Ok(())
     }
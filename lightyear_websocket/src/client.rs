@@ -51,6 +51,9 @@ pub enum WebSocketTarget {
     Url(String),
     /// Construct URL from scheme and [`PeerAddr`] component.
     Addr(WebSocketScheme),
+    /// Construct URL from scheme, [`PeerAddr`] component and path (e.g. "/game"),
+    /// for servers behind a reverse-proxy that routes on a subpath.
+    AddrWithPath(WebSocketScheme, String),
 }
 
 impl WebSocketClientIo {
@@ -69,6 +72,18 @@ impl WebSocketClientIo {
             target: WebSocketTarget::Addr(scheme),
         }
     }
+
+    /// Construct URL from scheme, [`PeerAddr`] and path.
+    pub fn from_addr_with_path(
+        config: ClientConfig,
+        scheme: WebSocketScheme,
+        path: impl Into<String>,
+    ) -> Self {
+        Self {
+            config,
+            target: WebSocketTarget::AddrWithPath(scheme, path.into()),
+        }
+    }
 }
 
 impl WebSocketClientPlugin {
@@ -87,6 +102,11 @@ impl WebSocketClientPlugin {
                     let server_addr = peer_addr.ok_or(WebSocketError::PeerAddrMissing)?.0;
                     format!("{}://{server_addr}", scheme.as_str())
                 }
+                WebSocketTarget::AddrWithPath(scheme, path) => {
+                    let server_addr = peer_addr.ok_or(WebSocketError::PeerAddrMissing)?.0;
+                    let path = path.strip_prefix('/').unwrap_or(path);
+                    format!("{}://{server_addr}/{path}", scheme.as_str())
+                }
             };
 
             let config = client.config.clone();